@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+/// Exponential backoff between retries, doubling the delay up to a maximum.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    maximum: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, maximum: Duration) -> Self {
+        Self {
+            initial,
+            maximum,
+            next: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.maximum);
+        delay
+    }
+
+    /// Starts over from the initial delay, e.g. after a successful attempt.
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_up_to_maximum() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(5));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    }
+}
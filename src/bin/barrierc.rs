@@ -1,30 +1,40 @@
+use barrier::backoff::Backoff;
+use barrier::client::{self, Client, Control, Handler, HandlerError};
+use barrier::clipboard::backend::{self, ClipboardBackend};
+use barrier::clipboard::{self, ClipboardData, Dropped, Reassembler, Selection, Update};
+use barrier::config::{Config, ConfigClipboard};
+use barrier::failover::Servers;
 use barrier::input::{Keyboard, Mouse};
-use barrier::parser::{parse_frame, Data, Message, Query};
-use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use barrier::language::{Languages, Switcher};
+use barrier::locks::Lock;
+use barrier::parser::{Clipboard, Command, Data, Grab, LegacyClipboard, Message, Query};
+use barrier::screen::Screen;
+use barrier::screensaver::Screensaver;
+use barrier::session::{Context, Feature, State};
+use barrier::transfer::{Progress, Receiver};
+use barrier::transport::{self, Stream};
+use snafu::{ResultExt, Snafu};
+use std::collections::HashMap;
+use std::sync::mpsc as std_mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::runtime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Could not get XDG base directory: {}", source))]
-    ConfigDir { source: xdg::BaseDirectoriesError },
-    #[snafu(display("Could not get config file"))]
-    ConfigFile {},
-    #[snafu(display("Could not merge config: {}", source))]
-    MergeConfig { source: config::ConfigError },
-    #[snafu(display("Could not parse config: {}", source))]
-    DeserializeConfig { source: config::ConfigError },
+    #[snafu(display("{}", source))]
+    LoadConfig { source: barrier::config::Error },
+    #[snafu(display("Could not start the event loop: {}", source))]
+    Runtime { source: std::io::Error },
     #[snafu(display("Create stream failed: {}", source))]
-    CreateStreamFailed { source: std::io::Error },
-    #[snafu(display("Clone stream failed: {}", source))]
-    CloneStreamFailed { source: std::io::Error },
-    #[snafu(display("Write to stream failed: {}", source))]
-    WriteStreamFailed { source: std::io::Error },
-    #[snafu(display("Read from stream failed: {}", source))]
-    ReadStreamFailed { source: std::io::Error },
+    CreateStreamFailed { source: barrier::transport::Error },
+    #[snafu(display("{}", source))]
+    Connection { source: client::Error },
+    #[snafu(display("Could not install signal handler: {}", source))]
+    SignalHandler { source: std::io::Error },
     #[snafu(display("Create device failed: {}", source))]
     CreateDeviceFailed { source: barrier::input::Error },
     #[snafu(display("Handling event failed: {}", source))]
@@ -33,16 +43,6 @@ pub enum Error {
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Config {
-    server: ConfigServer,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ConfigServer {
-    address: String,
-}
-
 fn main() {
     if let Err(err) = try_main() {
         eprintln!("{}", err);
@@ -51,143 +51,657 @@ fn main() {
 }
 
 fn try_main() -> Result<()> {
-    let xdg_dirs = xdg::BaseDirectories::with_prefix("barrier-rust").context(ConfigDir {})?;
-    let config_path = xdg_dirs
-        .find_config_file("config.toml")
-        .context(ConfigFile {})?;
-    let mut settings = config::Config::default();
-    settings
-        .merge(config::File::from(config_path))
-        .context(MergeConfig {})?
-        .merge(config::Environment::with_prefix("BARRIER_RUST"))
-        .context(MergeConfig {})?;
-    let config = settings
-        .try_into::<Config>()
-        .context(DeserializeConfig {})?;
+    let config = Config::load().context(LoadConfig {})?;
+    // Logs must not reach the server ahead of our hello.
+    if config.uses_stdio() {
+        transport::take_stdout().context(CreateStreamFailed {})?;
+    }
     println!("{:?}", config);
-    run(&config)
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context(Runtime {})?;
+    runtime.block_on(run(&config))
 }
 
-fn run(config: &Config) -> Result<()> {
-    let mouse = Mouse::new(1920, 1080).context(CreateDeviceFailed {})?;
-    let keyboard = Keyboard::new().context(CreateDeviceFailed {})?;
-    let stream =
-        TcpStream::connect(config.server.address.clone()).context(CreateStreamFailed {})?;
-    event_loop(stream, mouse, keyboard)
+/// Turns SIGHUP into re-reading the screen configuration, and SIGINT and
+/// SIGTERM into closing the connection cleanly.
+fn forward_signals(control: UnboundedSender<Control>) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context(SignalHandler {})?;
+    let mut interrupt = signal(SignalKind::interrupt()).context(SignalHandler {})?;
+    let mut terminate = signal(SignalKind::terminate()).context(SignalHandler {})?;
+    tokio::spawn(async move {
+        loop {
+            let command = tokio::select! {
+                _ = hangup.recv() => Control::Reload,
+                _ = interrupt.recv() => Control::Shutdown,
+                _ = terminate.recv() => Control::Shutdown,
+            };
+            if control.send(command).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
 }
 
-fn write_message(mut stream: &TcpStream, message: Vec<u8>) -> Result<()> {
-    println!("send raw message: {:x?}", message);
-    let mut response_buffer = Vec::new();
-    response_buffer.extend_from_slice(&(message.len() as u32).to_be_bytes());
-    response_buffer.extend_from_slice(&message);
-    stream
-        .write(&response_buffer)
-        .context(WriteStreamFailed {})?;
-    Ok(())
+async fn run(config: &Config) -> Result<()> {
+    let transform = config.screen.transform().context(LoadConfig {})?;
+    let screen = Screen::new(transform, config.screen.jump_zone);
+    let (abs_x_maximum, abs_y_maximum) = screen.transform().abs_maximum();
+    let mut devices = Devices {
+        screen,
+        mouse: Mouse::new(abs_x_maximum, abs_y_maximum).context(CreateDeviceFailed {})?,
+        keyboard: Keyboard::new().context(CreateDeviceFailed {})?,
+    };
+    let (outgoing_sender, mut outgoing) = mpsc::unbounded_channel();
+    let clipboards = ClipboardWorker::spawn(
+        backend::from_kind(config.clipboard.backend),
+        config.clipboard.clone(),
+        outgoing_sender,
+    );
+    let commands = CommandWorker::spawn(config.screensaver.clone(), config.languages.clone());
+    let (control_sender, mut control) = mpsc::unbounded_channel();
+    forward_signals(control_sender)?;
+
+    let result = serve(
+        config,
+        &mut devices,
+        &clipboards,
+        &commands,
+        &mut control,
+        &mut outgoing,
+    )
+    .await;
+    clipboards.stop();
+    commands.stop();
+    result
 }
 
-fn event_loop(mut stream: TcpStream, mut mouse: Mouse, mut keyboard: Keyboard) -> Result<()> {
-    let writer = stream.try_clone().unwrap();
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(2));
-        write_message(&writer, keep_alive()).unwrap();
-    });
+/// How a connection ended.
+enum Outcome {
+    Finished(Result<(), client::Error>),
+    /// A preferred server is reachable again.
+    FailBack(usize, Box<dyn Stream>),
+}
 
+/// Connects to the most preferred reachable server, failing over to the next
+/// one when the connection is lost and reconnecting while the server rejects
+/// our name as busy.
+async fn serve(
+    config: &Config,
+    devices: &mut Devices,
+    clipboards: &ClipboardWorker,
+    commands: &CommandWorker,
+    control: &mut UnboundedReceiver<Control>,
+    outgoing: &mut UnboundedReceiver<Message>,
+) -> Result<()> {
+    let configs = config.servers();
+    let transports = configs
+        .iter()
+        .map(|server| server.transport())
+        .collect::<Result<_, _>>()
+        .context(LoadConfig {})?;
+    let servers = Servers::new(transports);
+    let failback_interval = Duration::from_secs(config.failover.failback_interval);
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(15));
+    let mut busy_since = None;
+    let (mut current, mut stream) = servers.connect().await.context(CreateStreamFailed {})?;
     loop {
-        let mut frame_size_buffer = [0 as u8; 4];
-        stream
-            .read_exact(&mut frame_size_buffer)
-            .context(ReadStreamFailed {})?;
-        let frame_size = u32::from_be_bytes(frame_size_buffer) as usize;
-        // println!("receive message with frame size: {:?}", frame_size);
-
-        let mut buffer = vec![0; frame_size];
-        stream
-            .read_exact(&mut buffer[..frame_size])
-            .context(ReadStreamFailed {})?;
-
-        // println!("receive raw message: {:x?}", &buffer[..frame_size]);
-        let frame = parse_frame(&buffer[..frame_size]);
-        match frame {
-            Ok(frame) => {
-                let message = frame.1;
-                let response = handler(message, &mut mouse, &mut keyboard)?;
-                match response {
-                    Option::Some(response) => {
-                        write_message(&stream, response)?;
-                    }
-                    Option::None => {}
+        println!("Connected to server {:?}", configs[current].address);
+        // Drop whatever was queued for the previous connection.
+        while outgoing.try_recv().is_ok() {}
+        clipboards.send(ClipboardJob::Connect);
+        let mut app = App {
+            devices: &mut *devices,
+            clipboards,
+            files: Receiver::new(config.files.download_dir.clone()),
+            commands,
+            languages: &config.languages,
+        };
+        let mut client = Client::new(&config.name);
+        let state = client.watch_state();
+        let failback = async {
+            if !config.failover.failback || current == 0 {
+                return std::future::pending().await;
+            }
+            loop {
+                let found = servers.preferred(current, failback_interval).await;
+                // Switching while the cursor is on our screen would cut the
+                // user off mid keystroke, so wait for the next quiet moment.
+                if *state.borrow() != State::Active {
+                    break found;
                 }
             }
-            Err(e) => println!("Failed to parse frame: {:x?}", e),
+        };
+        let outcome = tokio::select! {
+            result = client.run(stream, &mut app, control, outgoing) => Outcome::Finished(result),
+            (index, preferred) = failback => Outcome::FailBack(index, preferred),
+        };
+        devices.release_all();
+        if client.established() {
+            busy_since = None;
+            backoff.reset();
+        }
+        match outcome {
+            Outcome::FailBack(index, preferred) => {
+                println!(
+                    "Server {:?} is reachable again, switching back",
+                    configs[index].address
+                );
+                busy_since = None;
+                backoff.reset();
+                current = index;
+                stream = preferred;
+            }
+            Outcome::Finished(Err(client::Error::Busy { name })) => {
+                // A busy name usually means the server still holds a stale
+                // connection of ours, which it drops once its keep alive
+                // times out.
+                let busy_retry_period = Duration::from_secs(configs[current].busy_retry_period);
+                let first_busy = *busy_since.get_or_insert_with(Instant::now);
+                if first_busy.elapsed() >= busy_retry_period {
+                    return Err(client::Error::Busy { name }).context(Connection {});
+                }
+                let delay = backoff.next_delay();
+                println!(
+                    "Server rejected screen name {:?} as busy, retrying in {:?}",
+                    name, delay
+                );
+                if !wait(delay, control, devices).await {
+                    return Ok(());
+                }
+                let (index, next) = servers
+                    .connect_from(current)
+                    .await
+                    .context(CreateStreamFailed {})?;
+                if index != current {
+                    busy_since = None;
+                    backoff.reset();
+                }
+                current = index;
+                stream = next;
+            }
+            Outcome::Finished(Err(e)) if e.is_disconnect() && servers.len() > 1 => {
+                println!("{}, failing over", e);
+                busy_since = None;
+                backoff.reset();
+                let (index, next) = servers
+                    .connect_from(current + 1)
+                    .await
+                    .context(CreateStreamFailed {})?;
+                current = index;
+                stream = next;
+            }
+            Outcome::Finished(result) => return result.context(Connection {}),
         }
     }
 }
 
-fn handler(
-    message: Message,
-    mouse: &mut Mouse,
-    keyboard: &mut Keyboard,
-) -> Result<Option<Vec<u8>>> {
-    println!("receive: {:?}", message);
-    match message {
-        Message::Hello(_) => Ok(Some(hello_back())),
-        Message::Query(Query::Info) => Ok(Some(info())),
-        Message::Data(Data::MouseMove(mousemove)) => {
-            mouse
-                .move_abs(mousemove.x as i32, mousemove.y as i32)
-                .context(HandleEvent {})?;
-            Ok(None)
+/// Waits for `delay` while following control commands, `false` when a
+/// shutdown was requested.
+async fn wait(
+    delay: Duration,
+    control: &mut UnboundedReceiver<Control>,
+    devices: &mut Devices,
+) -> bool {
+    let deadline = time::Instant::now() + delay;
+    loop {
+        tokio::select! {
+            _ = time::sleep_until(deadline) => return true,
+            command = control.recv() => match command {
+                Some(Control::Reload) => devices.reload(),
+                Some(Control::Shutdown) | None => return false,
+            },
         }
-        Message::Data(Data::MouseDown(mousedown)) => {
-            mouse.button_down(mousedown.id).context(HandleEvent {})?;
-            Ok(None)
+    }
+}
+
+struct Devices {
+    screen: Screen,
+    mouse: Mouse,
+    keyboard: Keyboard,
+}
+
+impl Devices {
+    /// Releases whatever the server left pressed, once it can no longer send
+    /// the releases itself.
+    fn release_all(&mut self) {
+        let keyboard = self.keyboard.release_all();
+        let mouse = self.mouse.release_all();
+        for e in keyboard.err().into_iter().chain(mouse.err()) {
+            println!("Failed to release held keys: {}", e);
         }
-        Message::Data(Data::MouseUp(mouseup)) => {
-            mouse.button_up(mouseup.id).context(HandleEvent {})?;
-            Ok(None)
+    }
+
+    /// Re-reads the screen configuration, recreating the mouse when the
+    /// range of its absolute axes changes.
+    fn reload(&mut self) {
+        if let Err(e) = self.try_reload() {
+            println!("Failed to reload screen: {}", e);
         }
-        Message::Data(Data::KeyDown(key)) => {
-            keyboard.key_down(key.button).context(HandleEvent {})?;
-            Ok(None)
+    }
+
+    fn try_reload(&mut self) -> Result<()> {
+        let config = Config::load().context(LoadConfig {})?;
+        let transform = config.screen.transform().context(LoadConfig {})?;
+        let (abs_x_maximum, abs_y_maximum) = transform.abs_maximum();
+        if (abs_x_maximum, abs_y_maximum) != self.screen.transform().abs_maximum() {
+            self.mouse = Mouse::new(abs_x_maximum, abs_y_maximum).context(CreateDeviceFailed {})?;
         }
-        Message::Data(Data::KeyUp(key)) => {
-            keyboard.key_up(key.button).context(HandleEvent {})?;
-            Ok(None)
+        self.screen.set_geometry(transform, config.screen.jump_zone);
+        Ok(())
+    }
+}
+
+/// Clipboard work, run in order on the clipboard thread.
+enum ClipboardJob {
+    Connect,
+    Enter(u32),
+    Leave(Context),
+    Push(Clipboard),
+    PushLegacy(LegacyClipboard),
+}
+
+/// Runs clipboard work on its own thread, as reading and writing the local
+/// clipboards spawns external programs. Messages for the server go out
+/// through the client's outgoing queue.
+struct ClipboardWorker {
+    jobs: std_mpsc::Sender<ClipboardJob>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ClipboardWorker {
+    fn spawn(
+        backend: Box<dyn ClipboardBackend>,
+        config: ConfigClipboard,
+        outgoing: UnboundedSender<Message>,
+    ) -> Self {
+        let (jobs, receiver) = std_mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut clipboards = Clipboards::new(backend, &config);
+            for job in receiver {
+                match job {
+                    ClipboardJob::Connect => clipboards.reset(),
+                    ClipboardJob::Enter(sequence_number) => clipboards.enter(sequence_number),
+                    ClipboardJob::Leave(context) => {
+                        for message in clipboards.leave(&context) {
+                            // Only fails once the client is gone.
+                            let _ = outgoing.send(message);
+                        }
+                    }
+                    ClipboardJob::Push(chunk) => clipboards.push(chunk),
+                    ClipboardJob::PushLegacy(legacy) => clipboards.push_legacy(legacy),
+                }
+            }
+        });
+        Self { jobs, thread }
+    }
+
+    fn send(&self, job: ClipboardJob) {
+        // Only fails when the thread panicked, which it already reported.
+        let _ = self.jobs.send(job);
+    }
+
+    /// Finishes the queued work and stops the thread.
+    fn stop(self) {
+        drop(self.jobs);
+        let _ = self.thread.join();
+    }
+}
+
+/// Work running external commands, run in order on the command thread.
+enum CommandJob {
+    Screensaver(bool),
+    Language(String),
+}
+
+/// Runs screensaver and keyboard layout commands on their own thread, so a
+/// slow command does not hold up the connection.
+struct CommandWorker {
+    jobs: std_mpsc::Sender<CommandJob>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl CommandWorker {
+    fn spawn(screensaver: Screensaver, languages: Languages) -> Self {
+        let (jobs, receiver) = std_mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut switcher = Switcher::new(&languages);
+            for job in receiver {
+                match job {
+                    CommandJob::Screensaver(active) => {
+                        if let Err(e) = screensaver.set_active(active) {
+                            println!("Failed to follow the server's screensaver: {}", e);
+                        }
+                    }
+                    CommandJob::Language(language) => {
+                        if let Err(e) = switcher.switch(&language) {
+                            println!("Failed to switch keyboard layout to {}: {}", language, e);
+                        }
+                    }
+                }
+            }
+        });
+        Self { jobs, thread }
+    }
+
+    fn send(&self, job: CommandJob) {
+        // Only fails when the thread panicked, which it already reported.
+        let _ = self.jobs.send(job);
+    }
+
+    /// Finishes the queued work and stops the thread.
+    fn stop(self) {
+        drop(self.jobs);
+        let _ = self.thread.join();
+    }
+}
+
+/// Clipboards shared with the server.
+struct Clipboards {
+    reassembler: Reassembler,
+    backend: Box<dyn ClipboardBackend>,
+    /// Selections shared with the server, the others are left alone.
+    selections: Vec<Selection>,
+    /// Sequence number of the last enter, which our grabs have to carry.
+    sequence_number: u32,
+    /// Local selections as of entering the screen, to tell whether they
+    /// changed while we were active.
+    entered: HashMap<Selection, ClipboardData>,
+}
+
+impl Clipboards {
+    fn new(backend: Box<dyn ClipboardBackend>, config: &ConfigClipboard) -> Self {
+        let selections = Selection::ALL
+            .iter()
+            .copied()
+            .filter(|selection| config.primary || *selection != Selection::Primary)
+            .collect();
+        Self {
+            reassembler: Reassembler::with_filter(config.filter.clone()),
+            backend,
+            selections,
+            sequence_number: 0,
+            entered: HashMap::new(),
         }
-        _ => Ok(None),
+    }
+
+    /// Forgets the state of the previous connection.
+    fn reset(&mut self) {
+        self.reassembler = Reassembler::with_filter(self.reassembler.filter().clone());
+        self.sequence_number = 0;
+        self.entered.clear();
+    }
+
+    fn read_local(&mut self, selection: Selection) -> Option<ClipboardData> {
+        match self.backend.get(selection) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Failed to read {:?} selection: {}", selection, e);
+                None
+            }
+        }
+    }
+
+    /// Remembers the current contents of `selection`.
+    fn snapshot(&mut self, selection: Selection) {
+        match self.read_local(selection) {
+            Some(data) => self.entered.insert(selection, data),
+            None => self.entered.remove(&selection),
+        };
+    }
+
+    fn push(&mut self, chunk: Clipboard) {
+        match self.reassembler.push(chunk) {
+            Ok(Some(update)) => self.receive(update),
+            Ok(None) => {}
+            Err(e) => println!("Dropped clipboard data: {}", e),
+        }
+    }
+
+    fn push_legacy(&mut self, legacy: LegacyClipboard) {
+        match self.reassembler.push_legacy(legacy) {
+            Ok(update) => self.receive(update),
+            Err(e) => println!("Dropped clipboard data: {}", e),
+        }
+    }
+
+    fn receive(&mut self, update: Update) {
+        println!(
+            "Received clipboard {} sequence number {}: text {:?}, html {:?}, bitmap {:?} bytes",
+            update.clipboard,
+            update.sequence_number,
+            update.data.text.as_ref().map(String::len),
+            update.data.html.as_ref().map(String::len),
+            update.data.bitmap.as_ref().map(Vec::len)
+        );
+        log_dropped(update.clipboard, &update.dropped);
+        let selection = match Selection::from_id(update.clipboard) {
+            Some(selection) if self.selections.contains(&selection) => selection,
+            _ => return,
+        };
+        if let Err(e) = self.backend.set(selection, &update.data) {
+            println!("Failed to set {:?} selection: {}", selection, e);
+        }
+        // Read back what the backend made of it, so it is not mistaken for
+        // a local copy and sent back.
+        self.snapshot(selection);
+    }
+
+    fn enter(&mut self, sequence_number: u32) {
+        self.sequence_number = sequence_number;
+        for selection in self.selections.clone() {
+            self.snapshot(selection);
+        }
+    }
+
+    /// Grabs and sends each selection that changed while our screen was
+    /// active.
+    fn leave(&mut self, context: &Context) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for selection in self.selections.clone() {
+            let mut data = match self.read_local(selection) {
+                Some(data) if Some(&data) != self.entered.get(&selection) => data,
+                _ => continue,
+            };
+            self.entered.remove(&selection);
+            log_dropped(selection.id(), &self.reassembler.filter().apply(&mut data));
+            if !data.is_empty() {
+                messages.extend(self.send(context, selection, &data));
+            }
+        }
+        messages
+    }
+
+    fn send(&self, context: &Context, selection: Selection, data: &ClipboardData) -> Vec<Message> {
+        let clipboard = selection.id();
+        let sequence_number = self.sequence_number;
+        let data = clipboard::encode(data);
+        let mut messages = vec![Message::Command(Command::Grab(Grab {
+            clipboard,
+            sequence_number,
+        }))];
+        if context.supports(Feature::ClipboardChunks) {
+            messages.extend(
+                clipboard::chunks(clipboard, sequence_number, &data)
+                    .into_iter()
+                    .map(|chunk| Message::Data(Data::Clipboard(chunk))),
+            );
+        } else {
+            messages.push(Message::Data(Data::LegacyClipboard(LegacyClipboard {
+                clipboard,
+                sequence_number,
+                data,
+            })));
+        }
+        messages
+    }
+}
+
+fn log_dropped(clipboard: u8, dropped: &[Dropped]) {
+    for dropped in dropped {
+        println!(
+            "Dropped {:?} of {} bytes from clipboard {}, it is filtered out or too large",
+            dropped.format, dropped.size, clipboard
+        );
     }
 }
 
-fn hello_back() -> Vec<u8> {
-    const MAJOR: u16 = 1;
-    const MINOR: u16 = 6;
-    const CLIENT_NAME: &'static [u8] = b"brendan-nom";
-    let mut v = Vec::new();
-    v.extend_from_slice(b"Barrier");
-    v.extend_from_slice(&MAJOR.to_be_bytes()[..]);
-    v.extend_from_slice(&MINOR.to_be_bytes()[..]);
-    v.extend_from_slice(&(CLIENT_NAME.len() as u32).to_be_bytes()[..]);
-    v.extend_from_slice(CLIENT_NAME);
-    v
+/// Everything a connection acts on.
+struct App<'a> {
+    devices: &'a mut Devices,
+    clipboards: &'a ClipboardWorker,
+    files: Receiver,
+    commands: &'a CommandWorker,
+    languages: &'a Languages,
 }
 
-fn info() -> Vec<u8> {
-    let mut v = Vec::new();
-    v.extend_from_slice(b"DINF");
-    v.extend_from_slice(&(0 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(0 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(2560 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(1440 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(0 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(1280 as u16).to_be_bytes()[..]);
-    v.extend_from_slice(&(720 as u16).to_be_bytes()[..]);
-    v
+impl Handler for App<'_> {
+    fn message(
+        &mut self,
+        context: &Context,
+        message: Message,
+    ) -> Result<Vec<Message>, HandlerError> {
+        let mut replies = self.handle(context, message)?;
+        replies.extend(self.geometry_update());
+        Ok(replies)
+    }
+
+    fn reload(&mut self) -> Result<Vec<Message>, HandlerError> {
+        self.devices.reload();
+        Ok(self.geometry_update().into_iter().collect())
+    }
 }
 
-fn keep_alive() -> Vec<u8> {
-    let mut v = Vec::new();
-    v.extend_from_slice(b"CALV");
-    v
+impl App<'_> {
+    /// Tells the server about our new geometry once it changed.
+    fn geometry_update(&mut self) -> Option<Message> {
+        if self.devices.screen.take_geometry_changed() {
+            Some(Message::Data(Data::Info(self.devices.screen.info())))
+        } else {
+            None
+        }
+    }
+
+    fn handle(&mut self, context: &Context, message: Message) -> Result<Vec<Message>> {
+        println!("receive: {:?}", message);
+        let Devices {
+            screen,
+            mouse,
+            keyboard,
+        } = &mut *self.devices;
+        match message {
+            Message::Query(Query::Info) => Ok(vec![Message::Data(Data::Info(screen.info()))]),
+            Message::Command(Command::InfoAck) => {
+                screen.info_acknowledged();
+                Ok(Vec::new())
+            }
+            Message::Command(Command::Enter(enter)) => {
+                self.clipboards
+                    .send(ClipboardJob::Enter(enter.sequence_number));
+                keyboard
+                    .sync_locks(enter.key_modifier_mask)
+                    .context(HandleEvent {})?;
+                if let Some((x, y)) = screen.move_cursor(enter.x, enter.y) {
+                    mouse.move_abs(x, y).context(HandleEvent {})?;
+                }
+                Ok(Vec::new())
+            }
+            Message::Command(Command::Leave) => {
+                let released = keyboard.release_all();
+                mouse.release_all().context(HandleEvent {})?;
+                released.context(HandleEvent {})?;
+                self.clipboards.send(ClipboardJob::Leave(context.clone()));
+                Ok(Vec::new())
+            }
+            Message::Command(Command::Screensaver(active)) => {
+                self.commands.send(CommandJob::Screensaver(active));
+                Ok(Vec::new())
+            }
+            Message::Command(Command::ResetOptions) => {
+                keyboard.reset_options();
+                Ok(Vec::new())
+            }
+            Message::Data(Data::Options(options)) => {
+                keyboard.set_options(&options.options);
+                Ok(Vec::new())
+            }
+            Message::Data(Data::MouseMove(mousemove)) => {
+                if let Some((x, y)) = screen.move_cursor(mousemove.x, mousemove.y) {
+                    mouse.move_abs(x, y).context(HandleEvent {})?;
+                }
+                Ok(Vec::new())
+            }
+            Message::Data(Data::MouseDown(mousedown)) => {
+                mouse.button_down(mousedown.id).context(HandleEvent {})?;
+                Ok(Vec::new())
+            }
+            Message::Data(Data::MouseUp(mouseup)) => {
+                mouse.button_up(mouseup.id).context(HandleEvent {})?;
+                Ok(Vec::new())
+            }
+            Message::Data(Data::Languages(languages)) => {
+                let missing = self.languages.missing(&languages);
+                if !missing.is_empty() {
+                    println!(
+                        "No keyboard layout configured for the server's languages {:?}",
+                        missing
+                    );
+                }
+                Ok(Vec::new())
+            }
+            Message::Data(Data::KeyDown(key)) => {
+                // The switch is not waited for, a slow command may let the
+                // first key after a language change land in the old layout.
+                if let Some(language) = key.language.clone() {
+                    self.commands.send(CommandJob::Language(language));
+                }
+                if Lock::from_button(key.button).is_none() {
+                    keyboard
+                        .sync_locks(key.modifier_mask)
+                        .context(HandleEvent {})?;
+                }
+                keyboard.key_down(key.button).context(HandleEvent {})?;
+                Ok(Vec::new())
+            }
+            Message::Data(Data::KeyUp(key)) => {
+                keyboard.key_up(key.button).context(HandleEvent {})?;
+                Ok(Vec::new())
+            }
+            Message::Data(Data::Clipboard(chunk)) => {
+                self.clipboards.send(ClipboardJob::Push(chunk));
+                Ok(Vec::new())
+            }
+            Message::Data(Data::LegacyClipboard(legacy)) => {
+                self.clipboards.send(ClipboardJob::PushLegacy(legacy));
+                Ok(Vec::new())
+            }
+            Message::Data(Data::DragInfo(info)) => {
+                self.files.drag(info);
+                Ok(Vec::new())
+            }
+            Message::Data(Data::FileTransfer(chunk)) => {
+                match self.files.push(chunk) {
+                    Ok(Some(progress)) => report_progress(progress),
+                    Ok(None) => {}
+                    Err(e) => println!("File transfer failed: {}", e),
+                }
+                Ok(Vec::new())
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+fn report_progress(progress: Progress) {
+    match progress {
+        Progress::Started { name, size } => {
+            println!("Receiving {:?}, {} bytes", name, size)
+        }
+        Progress::Received { name, percent } => println!("Receiving {:?}, {}%", name, percent),
+        Progress::Finished { path, size } => {
+            println!("Received {}, {} bytes", path.display(), size)
+        }
+    }
 }
@@ -0,0 +1,557 @@
+use crate::parser::{
+    parse_frame, Command, Error as ServerError, HelloBack, Message, ProtocolVersion,
+};
+use crate::session::{self, Context, Disposition, Session, State};
+use crate::writer::Writer;
+use snafu::{ResultExt, Snafu};
+use std::io;
+use std::time::Duration;
+use tokio::io::{self as async_io, AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Read from stream failed: {}", source))]
+    Read { source: io::Error },
+    #[snafu(display("Write to stream failed: {}", source))]
+    Write { source: io::Error },
+    #[snafu(display("Server closed the connection"))]
+    Closed {},
+    #[snafu(display("Server sent nothing for {:?}", timeout))]
+    Timeout { timeout: Duration },
+    #[snafu(display("Handshake did not finish within {:?}", timeout))]
+    HandshakeTimeout { timeout: Duration },
+    #[snafu(display("Protocol negotiation failed: {}", source))]
+    Negotiate { source: session::Error },
+    #[snafu(display("{}", source))]
+    Protocol { source: session::Error },
+    #[snafu(display(
+        "Server rejected our protocol version, it speaks {}.{}",
+        version.major,
+        version.minor
+    ))]
+    Incompatible { version: ProtocolVersion },
+    #[snafu(display("Server rejected screen name {:?} as busy", name))]
+    Busy { name: String },
+    #[snafu(display("Server does not know screen name {:?}", name))]
+    UnknownName { name: String },
+    #[snafu(display("Server rejected a message of ours as malformed"))]
+    Rejected {},
+    #[snafu(display("{}", source))]
+    Handle { source: HandlerError },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Whether the connection itself was lost, so another server may do.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            Error::Read { .. }
+                | Error::Write { .. }
+                | Error::Closed {}
+                | Error::Timeout { .. }
+                | Error::HandshakeTimeout { .. }
+        )
+    }
+}
+
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How often the server sends keep alives, which we echo.
+pub const KEEP_ALIVE_RATE: Duration = Duration::from_secs(3);
+
+/// Missed keep alives after which the server is considered gone.
+const KEEP_ALIVES_UNTIL_DEATH: u32 = 3;
+
+/// Commands from outside the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Control {
+    /// Re-read the configuration.
+    Reload,
+    /// Close the connection and stop.
+    Shutdown,
+}
+
+/// Acts on the server's messages once the handshake is done.
+pub trait Handler {
+    /// Handles a message from the server, returning the replies.
+    fn message(
+        &mut self,
+        context: &Context,
+        message: Message,
+    ) -> Result<Vec<Message>, HandlerError>;
+
+    /// Handles [`Control::Reload`], returning messages for the server.
+    fn reload(&mut self) -> Result<Vec<Message>, HandlerError>;
+}
+
+/// Removes the first complete frame from `buffer`, without its length.
+fn take_frame(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buffer.len() < 4 {
+        return None;
+    }
+    let size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if buffer.len() < 4 + size {
+        return None;
+    }
+    let frame = buffer[4..4 + size].to_vec();
+    buffer.drain(..4 + size);
+    Some(frame)
+}
+
+/// A connection to the server.
+///
+/// Reading, the keep alive timeout, messages queued by background work such
+/// as clipboard reads and control commands all run on one event loop.
+#[derive(Debug)]
+pub struct Client {
+    name: String,
+    context: Context,
+    session: Session,
+    timeout: Duration,
+    states: (watch::Sender<State>, watch::Receiver<State>),
+    established: bool,
+}
+
+impl Client {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            context: Context::default(),
+            session: Session::default(),
+            timeout: KEEP_ALIVE_RATE * KEEP_ALIVES_UNTIL_DEATH,
+            states: watch::channel(State::Connecting),
+            established: false,
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn state(&self) -> State {
+        self.session.state()
+    }
+
+    /// Follows the session state while `run` holds the client.
+    pub fn watch_state(&self) -> watch::Receiver<State> {
+        self.states.1.clone()
+    }
+
+    /// Whether the last `run` got past the handshake.
+    pub fn established(&self) -> bool {
+        self.established
+    }
+
+    /// Runs the connection until the server goes away, an error occurs or
+    /// a shutdown is requested, which closes the connection cleanly.
+    pub async fn run<S, H>(
+        &mut self,
+        stream: S,
+        handler: &mut H,
+        control: &mut UnboundedReceiver<Control>,
+        outgoing: &mut UnboundedReceiver<Message>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        H: Handler,
+    {
+        self.established = false;
+        self.session.connected(Instant::now().into_std());
+        self.publish();
+        let result = self.event_loop(stream, handler, control, outgoing).await;
+        self.session.close();
+        self.publish();
+        result
+    }
+
+    async fn event_loop<S, H>(
+        &mut self,
+        stream: S,
+        handler: &mut H,
+        control: &mut UnboundedReceiver<Control>,
+        outgoing: &mut UnboundedReceiver<Message>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        H: Handler,
+    {
+        let (mut reader, writer) = async_io::split(stream);
+        let mut writer = Writer::spawn(writer);
+        let mut buffer = Vec::new();
+        let mut deadline = Instant::now() + self.timeout;
+        loop {
+            let handshake_deadline = self.session.handshake_deadline().map(Instant::from_std);
+            tokio::select! {
+                read = reader.read_buf(&mut buffer) => {
+                    if read.context(Read {})? == 0 {
+                        return Closed {}.fail();
+                    }
+                    deadline = Instant::now() + self.timeout;
+                    while let Some(frame) = take_frame(&mut buffer) {
+                        writer.send_all(self.receive(&frame, handler)?);
+                    }
+                }
+                Some(message) = outgoing.recv() => writer.send(message),
+                command = control.recv() => match command {
+                    Some(Control::Reload) => {
+                        writer.send_all(handler.reload().context(Handle {})?);
+                    }
+                    Some(Control::Shutdown) | None => {
+                        self.session.close();
+                        self.publish();
+                        // We are stopping either way, so this is no reason
+                        // to reconnect.
+                        if let Err(e) = writer.finish(KEEP_ALIVE_RATE).await {
+                            println!("Could not close the connection cleanly: {}", e);
+                        }
+                        return Ok(());
+                    }
+                },
+                result = writer.stopped() => {
+                    result.context(Write {})?;
+                    return Closed {}.fail();
+                }
+                _ = time::sleep_until(deadline) => {
+                    return Timeout { timeout: self.timeout }.fail();
+                }
+                _ = time::sleep_until(handshake_deadline.unwrap_or(deadline)),
+                    if handshake_deadline.is_some() =>
+                {
+                    return HandshakeTimeout {
+                        timeout: self.session.handshake_timeout(),
+                    }
+                    .fail();
+                }
+            }
+        }
+    }
+
+    /// Tells watchers about a state change.
+    fn publish(&mut self) {
+        let state = self.session.state();
+        if matches!(state, State::Inactive | State::Active) {
+            self.established = true;
+        }
+        if *self.states.1.borrow() != state {
+            // Cannot fail, we hold a receiver ourselves.
+            let _ = self.states.0.send(state);
+        }
+    }
+
+    fn receive<H: Handler>(&mut self, frame: &[u8], handler: &mut H) -> Result<Vec<Message>> {
+        let message = match parse_frame(frame, &self.context) {
+            Ok((_, message)) => message,
+            Err(e) => {
+                println!("Failed to parse frame: {:x?}", e);
+                return Ok(Vec::new());
+            }
+        };
+        let state = self.session.state();
+        if self.session.receive(&message).context(Protocol {})? == Disposition::Drop {
+            println!("Dropped {:?} while {}", message, state);
+            return Ok(Vec::new());
+        }
+        if self.session.state() != state {
+            println!("Session {} -> {}", state, self.session.state());
+            self.publish();
+        }
+        match message {
+            Message::Hello(hello) => {
+                self.context = Context::negotiate(&hello).context(Negotiate {})?;
+                println!(
+                    "Negotiated protocol version {}.{} with {:?} server",
+                    self.context.version.major, self.context.version.minor, self.context.flavour
+                );
+                Ok(vec![Message::HelloBack(HelloBack {
+                    flavour: self.context.flavour,
+                    client_version: self.context.version,
+                    name: self.name.clone(),
+                })])
+            }
+            Message::Error(ServerError::Incompatible(version)) => Incompatible { version }.fail(),
+            Message::Error(ServerError::Busy) => Busy {
+                name: self.name.clone(),
+            }
+            .fail(),
+            Message::Error(ServerError::Unkown) => UnknownName {
+                name: self.name.clone(),
+            }
+            .fail(),
+            Message::Error(ServerError::Bad) => Rejected {}.fail(),
+            Message::Command(Command::KeepAlive) => Ok(vec![Message::Command(Command::KeepAlive)]),
+            message => handler.message(&self.context, message).context(Handle {}),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Query;
+    use crate::writer::frame;
+    use hex_literal::hex;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    #[derive(Default)]
+    struct Recorder {
+        messages: Vec<Message>,
+        reloads: usize,
+    }
+
+    impl Handler for Recorder {
+        fn message(
+            &mut self,
+            _context: &Context,
+            message: Message,
+        ) -> Result<Vec<Message>, HandlerError> {
+            self.messages.push(message);
+            Ok(Vec::new())
+        }
+
+        fn reload(&mut self) -> Result<Vec<Message>, HandlerError> {
+            self.reloads += 1;
+            Ok(vec![Message::Command(Command::KeepAlive)])
+        }
+    }
+
+    async fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(client, listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    async fn read_frame(server: &mut TcpStream) -> Vec<u8> {
+        let size = server.read_u32().await.unwrap();
+        let mut frame = vec![0; size as usize];
+        server.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[test]
+    fn take_frames() {
+        let mut buffer = frame(&Message::Command(Command::KeepAlive));
+        buffer.extend_from_slice(&hex!("00 00 00 04 51 49"));
+        assert_eq!(take_frame(&mut buffer), Some(b"CALV".to_vec()));
+        assert_eq!(take_frame(&mut buffer), None);
+        buffer.extend_from_slice(b"NF");
+        assert_eq!(take_frame(&mut buffer), Some(b"QINF".to_vec()));
+        assert!(buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_keep_alive_and_shutdown() {
+        let (stream, mut server) = connect().await;
+        let (control_sender, mut control) = mpsc::unbounded_channel();
+        let (outgoing_sender, mut outgoing) = mpsc::unbounded_channel();
+        let server = async move {
+            server
+                .write_all(&hex!(
+                    "00 00 00 0b 42 61 72 72 69 65 72 00 01 00 08
+                     00 00 00 04 43 41 4c 56
+                     00 00 00 04 51 49 4e 46"
+                ))
+                .await
+                .unwrap();
+            let hello_back = read_frame(&mut server).await;
+            assert_eq!(&hello_back[..11], b"Barrier\x00\x01\x00\x08");
+            assert_eq!(read_frame(&mut server).await, b"CALV");
+            outgoing_sender
+                .send(Message::Command(Command::KeepAlive))
+                .unwrap();
+            assert_eq!(read_frame(&mut server).await, b"CALV");
+            control_sender.send(Control::Reload).unwrap();
+            assert_eq!(read_frame(&mut server).await, b"CALV");
+            control_sender.send(Control::Shutdown).unwrap();
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        };
+        let mut client = Client::new("test");
+        let mut recorder = Recorder::default();
+        let (result, ()) = tokio::join!(
+            client.run(stream, &mut recorder, &mut control, &mut outgoing),
+            server
+        );
+        result.unwrap();
+        assert_eq!(client.context().version.minor, 8);
+        assert_eq!(client.state(), State::Closing);
+        assert_eq!(recorder.messages, [Message::Query(Query::Info)]);
+        assert_eq!(recorder.reloads, 1);
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_closed() {
+        let (stream, mut server) = connect().await;
+        let (_control_sender, mut control) = mpsc::unbounded_channel();
+        let (_outgoing_sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+        server
+            .write_all(&hex!("00 00 00 04 45 42 53 59"))
+            .await
+            .unwrap();
+        let mut client = Client::new("test");
+        match client
+            .run(
+                stream,
+                &mut Recorder::default(),
+                &mut control,
+                &mut outgoing,
+            )
+            .await
+        {
+            Err(Error::Busy { name }) => assert_eq!(name, "test"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(!client.established());
+
+        let (stream, mut server) = connect().await;
+        server
+            .write_all(&hex!("00 00 00 04 45 55 4e 4b"))
+            .await
+            .unwrap();
+        match client
+            .run(
+                stream,
+                &mut Recorder::default(),
+                &mut control,
+                &mut outgoing,
+            )
+            .await
+        {
+            Err(Error::UnknownName { name }) => assert_eq!(name, "test"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let (stream, mut server) = connect().await;
+        server
+            .write_all(&hex!("00 00 00 04 45 42 41 44"))
+            .await
+            .unwrap();
+        match client
+            .run(
+                stream,
+                &mut Recorder::default(),
+                &mut control,
+                &mut outgoing,
+            )
+            .await
+        {
+            Err(Error::Rejected {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let (stream, server) = connect().await;
+        drop(server);
+        match client
+            .run(
+                stream,
+                &mut Recorder::default(),
+                &mut control,
+                &mut outgoing,
+            )
+            .await
+        {
+            Err(Error::Closed {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn state_is_watched() {
+        let (stream, mut server) = connect().await;
+        let (_control_sender, mut control) = mpsc::unbounded_channel();
+        let (_outgoing_sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+        let mut client = Client::new("test");
+        let mut state = client.watch_state();
+        let server = async move {
+            server
+                .write_all(&hex!("00 00 00 0b 42 61 72 72 69 65 72 00 01 00 08"))
+                .await
+                .unwrap();
+            read_frame(&mut server).await;
+            server
+                .write_all(&hex!(
+                    "00 00 00 04 43 49 41 4b
+                     00 00 00 0e 43 49 4e 4e 00 0a 00 14 00 00 00 01 00 00"
+                ))
+                .await
+                .unwrap();
+            while *state.borrow() != State::Active {
+                state.changed().await.unwrap();
+            }
+            state
+        };
+        let mut recorder = Recorder::default();
+        let (result, state) = tokio::join!(
+            client.run(stream, &mut recorder, &mut control, &mut outgoing),
+            server
+        );
+        assert!(result.unwrap_err().is_disconnect());
+        assert!(client.established());
+        assert_eq!(*state.borrow(), State::Closing);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_server_times_out() {
+        let (stream, _server) = connect().await;
+        let (_control_sender, mut control) = mpsc::unbounded_channel();
+        let (_outgoing_sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+        let mut client = Client::new("test");
+        match client
+            .run(
+                stream,
+                &mut Recorder::default(),
+                &mut control,
+                &mut outgoing,
+            )
+            .await
+        {
+            Err(Error::Timeout { timeout }) => assert_eq!(timeout, Duration::from_secs(9)),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_handshake_times_out() {
+        let (stream, mut server) = connect().await;
+        let (_control_sender, mut control) = mpsc::unbounded_channel();
+        let (_outgoing_sender, mut outgoing) = mpsc::unbounded_channel::<Message>();
+        // Keep alives but never an info acknowledgement.
+        let server = async move {
+            server
+                .write_all(&hex!("00 00 00 0b 42 61 72 72 69 65 72 00 01 00 08"))
+                .await
+                .unwrap();
+            loop {
+                time::sleep(KEEP_ALIVE_RATE).await;
+                if server
+                    .write_all(&hex!("00 00 00 04 43 41 4c 56"))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        };
+        let mut client = Client::new("test");
+        let mut recorder = Recorder::default();
+        let result = tokio::select! {
+            result = client.run(stream, &mut recorder, &mut control, &mut outgoing) => result,
+            _ = server => unreachable!(),
+        };
+        match result {
+            Err(Error::HandshakeTimeout { timeout }) => {
+                assert_eq!(timeout, Duration::from_secs(15))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(client.state(), State::Closing);
+    }
+}
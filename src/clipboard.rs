@@ -0,0 +1,768 @@
+use crate::parser::{Clipboard, LegacyClipboard, Mark};
+use nom::error::ErrorKind;
+use nom::multi::length_data;
+use nom::number::complete::be_u32;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, Snafu};
+use std::collections::HashMap;
+
+pub mod backend;
+pub mod convert;
+
+#[derive(Debug, Snafu, PartialEq)]
+pub enum Error {
+    #[snafu(display("Invalid clipboard {} transfer size {:?}", clipboard, size))]
+    InvalidSize { clipboard: u8, size: String },
+    #[snafu(display(
+        "Clipboard {} chunk for sequence number {} without a transfer in progress",
+        clipboard,
+        sequence_number
+    ))]
+    UnexpectedChunk { clipboard: u8, sequence_number: u32 },
+    #[snafu(display(
+        "Clipboard {} transfer size mismatch, expected {} bytes but received {}",
+        clipboard,
+        expected,
+        actual
+    ))]
+    SizeMismatch {
+        clipboard: u8,
+        expected: usize,
+        actual: usize,
+    },
+    #[snafu(display("Could not decode clipboard {}", clipboard))]
+    Decode { clipboard: u8 },
+    #[snafu(display(
+        "Clipboard {} transfer of {} bytes exceeds the limit of {} bytes",
+        clipboard,
+        size,
+        limit
+    ))]
+    TooLarge {
+        clipboard: u8,
+        size: usize,
+        limit: usize,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Barrier's clipboard format ids.
+const FORMAT_TEXT: u32 = 0;
+const FORMAT_HTML: u32 = 1;
+const FORMAT_BITMAP: u32 = 2;
+
+/// The clipboards Barrier shares, by their id on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Selection {
+    /// The regular clipboard, id 0.
+    Clipboard,
+    /// The X11 and Wayland primary selection used for middle click paste,
+    /// id 1.
+    Primary,
+}
+
+impl Selection {
+    pub const ALL: [Selection; 2] = [Selection::Clipboard, Selection::Primary];
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Selection::Clipboard),
+            1 => Some(Selection::Primary),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            Selection::Clipboard => 0,
+            Selection::Primary => 1,
+        }
+    }
+}
+
+/// Clipboard contents in each of the formats the server offered.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ClipboardData {
+    pub text: Option<String>,
+    pub html: Option<String>,
+    /// Device independent bitmap, a BMP file without its file header.
+    pub bitmap: Option<Vec<u8>>,
+}
+
+impl ClipboardData {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none() && self.html.is_none() && self.bitmap.is_none()
+    }
+}
+
+/// The formats a clipboard can hold.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Html,
+    Bitmap,
+}
+
+/// A format the filter removed from a clipboard.
+#[derive(Debug, PartialEq)]
+pub struct Dropped {
+    pub format: Format,
+    pub size: usize,
+}
+
+/// Which clipboard formats are shared and how large they may be.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Filter {
+    /// Formats to share, all of them by default.
+    #[serde(default = "default_formats")]
+    pub formats: Vec<Format>,
+    /// Only share plain text, whatever `formats` says.
+    #[serde(default)]
+    pub text_only: bool,
+    /// Size limits in bytes.
+    #[serde(default = "default_max_text_size")]
+    pub max_text_size: usize,
+    #[serde(default = "default_max_html_size")]
+    pub max_html_size: usize,
+    #[serde(default = "default_max_bitmap_size")]
+    pub max_bitmap_size: usize,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            formats: default_formats(),
+            text_only: false,
+            max_text_size: default_max_text_size(),
+            max_html_size: default_max_html_size(),
+            max_bitmap_size: default_max_bitmap_size(),
+        }
+    }
+}
+
+fn default_formats() -> Vec<Format> {
+    vec![Format::Text, Format::Html, Format::Bitmap]
+}
+
+fn default_max_text_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_html_size() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_max_bitmap_size() -> usize {
+    32 * 1024 * 1024
+}
+
+impl Filter {
+    pub fn allows(&self, format: Format) -> bool {
+        if self.text_only {
+            format == Format::Text
+        } else {
+            self.formats.contains(&format)
+        }
+    }
+
+    pub fn max_size(&self, format: Format) -> usize {
+        match format {
+            Format::Text => self.max_text_size,
+            Format::Html => self.max_html_size,
+            Format::Bitmap => self.max_bitmap_size,
+        }
+    }
+
+    /// Largest transfer that could hold anything we keep: every allowed
+    /// format at its limit plus the serialization's headers.
+    pub fn max_transfer_size(&self) -> usize {
+        [Format::Text, Format::Html, Format::Bitmap]
+            .iter()
+            .filter(|format| self.allows(**format))
+            .fold(4, |size, format| {
+                size.saturating_add(8)
+                    .saturating_add(self.max_size(*format))
+            })
+    }
+
+    /// Removes the formats that are not allowed or over their limit.
+    pub fn apply(&self, data: &mut ClipboardData) -> Vec<Dropped> {
+        let mut dropped = Vec::new();
+        let mut check = |format, size: Option<usize>| match size {
+            Some(size) if !self.allows(format) || size > self.max_size(format) => {
+                dropped.push(Dropped { format, size });
+                false
+            }
+            _ => true,
+        };
+        if !check(Format::Text, data.text.as_ref().map(String::len)) {
+            data.text = None;
+        }
+        if !check(Format::Html, data.html.as_ref().map(String::len)) {
+            data.html = None;
+        }
+        if !check(Format::Bitmap, data.bitmap.as_ref().map(Vec::len)) {
+            data.bitmap = None;
+        }
+        dropped
+    }
+}
+
+fn format(input: &[u8]) -> nom::IResult<&[u8], (u32, &[u8])> {
+    let (input, id) = be_u32(input)?;
+    let (input, data) = length_data(be_u32)(input)?;
+    Ok((input, (id, data)))
+}
+
+/// Decodes Barrier's clipboard serialization: the number of formats followed
+/// by the id, size and data of each. Formats we do not know are skipped.
+pub fn decode(input: &[u8]) -> Option<ClipboardData> {
+    let (mut input, count) = be_u32::<(&[u8], ErrorKind)>(input).ok()?;
+    let mut data = ClipboardData::default();
+    for _ in 0..count {
+        let (rest, (id, bytes)) = format(input).ok()?;
+        input = rest;
+        match id {
+            FORMAT_TEXT => data.text = Some(String::from_utf8_lossy(bytes).into_owned()),
+            FORMAT_HTML => data.html = Some(String::from_utf8_lossy(bytes).into_owned()),
+            FORMAT_BITMAP => data.bitmap = Some(bytes.to_vec()),
+            _ => {}
+        }
+    }
+    Some(data)
+}
+
+fn encode_format(v: &mut Vec<u8>, id: u32, data: &[u8]) {
+    v.extend_from_slice(&id.to_be_bytes());
+    v.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    v.extend_from_slice(data);
+}
+
+/// Encodes `data` in Barrier's clipboard serialization, the inverse of
+/// [`decode`].
+pub fn encode(data: &ClipboardData) -> Vec<u8> {
+    let formats = [
+        (FORMAT_TEXT, data.text.as_ref().map(String::as_bytes)),
+        (FORMAT_HTML, data.html.as_ref().map(String::as_bytes)),
+        (FORMAT_BITMAP, data.bitmap.as_deref()),
+    ];
+    let mut v = Vec::new();
+    let count = formats.iter().filter(|(_, data)| data.is_some()).count() as u32;
+    v.extend_from_slice(&count.to_be_bytes());
+    for (id, data) in &formats {
+        if let Some(data) = data {
+            encode_format(&mut v, *id, data);
+        }
+    }
+    v
+}
+
+/// Size of the data chunks in a transfer we send.
+const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Splits encoded clipboard `data` into a transfer: a start chunk holding
+/// its size, the data chunks and an end chunk.
+pub fn chunks(clipboard: u8, sequence_number: u32, data: &[u8]) -> Vec<Clipboard> {
+    let chunk = |mark, data: &[u8]| Clipboard {
+        clipboard,
+        sequence_number,
+        mark,
+        data: data.to_vec(),
+    };
+    let mut chunks = vec![chunk(Mark::Start, data.len().to_string().as_bytes())];
+    chunks.extend(data.chunks(CHUNK_SIZE).map(|part| chunk(Mark::Chunk, part)));
+    chunks.push(chunk(Mark::End, b""));
+    chunks
+}
+
+/// A clipboard whose transfer has completed.
+#[derive(Debug, PartialEq)]
+pub struct Update {
+    pub clipboard: u8,
+    pub sequence_number: u32,
+    pub data: ClipboardData,
+    /// Formats the filter removed from `data`.
+    pub dropped: Vec<Dropped>,
+}
+
+#[derive(Debug)]
+struct Transfer {
+    sequence_number: u32,
+    size: usize,
+    data: Vec<u8>,
+    /// Set for transfers over the size limit, whose chunks are dropped.
+    skip: bool,
+}
+
+/// Reassembles chunked clipboard transfers, one per clipboard id.
+///
+/// A transfer starts with a chunk holding the total size, followed by data
+/// chunks and an end chunk, all with the same sequence number. A new start
+/// replaces any transfer still in progress for that clipboard.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    transfers: HashMap<u8, Transfer>,
+    filter: Filter,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_filter(filter: Filter) -> Self {
+        Self {
+            transfers: HashMap::new(),
+            filter,
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    fn update(&self, clipboard: u8, sequence_number: u32, encoded: &[u8]) -> Result<Update> {
+        let mut data = decode(encoded).context(Decode { clipboard })?;
+        let dropped = self.filter.apply(&mut data);
+        Ok(Update {
+            clipboard,
+            sequence_number,
+            data,
+            dropped,
+        })
+    }
+
+    /// Adds a chunk, returning the decoded clipboard once its transfer is
+    /// complete.
+    pub fn push(&mut self, chunk: Clipboard) -> Result<Option<Update>> {
+        let clipboard = chunk.clipboard;
+        let sequence_number = chunk.sequence_number;
+        match chunk.mark {
+            Mark::Start => {
+                let size = String::from_utf8_lossy(&chunk.data);
+                let size = size.trim().parse::<usize>().ok().context(InvalidSize {
+                    clipboard,
+                    size: size.to_string(),
+                })?;
+                let limit = self.filter.max_transfer_size();
+                let skip = size > limit;
+                self.transfers.insert(
+                    clipboard,
+                    Transfer {
+                        sequence_number,
+                        size,
+                        data: Vec::new(),
+                        skip,
+                    },
+                );
+                ensure!(
+                    !skip,
+                    TooLarge {
+                        clipboard,
+                        size,
+                        limit
+                    }
+                );
+                Ok(None)
+            }
+            Mark::Chunk => {
+                let transfer = self
+                    .transfers
+                    .get_mut(&clipboard)
+                    .filter(|transfer| transfer.sequence_number == sequence_number)
+                    .context(UnexpectedChunk {
+                        clipboard,
+                        sequence_number,
+                    })?;
+                if transfer.skip {
+                    return Ok(None);
+                }
+                transfer.data.extend_from_slice(&chunk.data);
+                let (expected, actual) = (transfer.size, transfer.data.len());
+                if actual > expected {
+                    self.transfers.remove(&clipboard);
+                    return SizeMismatch {
+                        clipboard,
+                        expected,
+                        actual,
+                    }
+                    .fail();
+                }
+                Ok(None)
+            }
+            Mark::End => {
+                let transfer = match self.transfers.remove(&clipboard) {
+                    Some(transfer) if transfer.sequence_number == sequence_number => transfer,
+                    transfer => {
+                        // A stale end must not cancel a newer transfer.
+                        if let Some(transfer) = transfer {
+                            self.transfers.insert(clipboard, transfer);
+                        }
+                        return UnexpectedChunk {
+                            clipboard,
+                            sequence_number,
+                        }
+                        .fail();
+                    }
+                };
+                if transfer.skip {
+                    return Ok(None);
+                }
+                ensure!(
+                    transfer.data.len() == transfer.size,
+                    SizeMismatch {
+                        clipboard,
+                        expected: transfer.size,
+                        actual: transfer.data.len(),
+                    }
+                );
+                self.update(clipboard, sequence_number, &transfer.data)
+                    .map(Some)
+            }
+        }
+    }
+
+    /// Decodes a clipboard sent in a single message before protocol 1.6.
+    pub fn push_legacy(&mut self, legacy: LegacyClipboard) -> Result<Update> {
+        let clipboard = legacy.clipboard;
+        self.transfers.remove(&clipboard);
+        let limit = self.filter.max_transfer_size();
+        ensure!(
+            legacy.data.len() <= limit,
+            TooLarge {
+                clipboard,
+                size: legacy.data.len(),
+                limit
+            }
+        );
+        self.update(clipboard, legacy.sequence_number, &legacy.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marshal(formats: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend_from_slice(&(formats.len() as u32).to_be_bytes());
+        for (id, data) in formats {
+            v.extend_from_slice(&id.to_be_bytes());
+            v.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            v.extend_from_slice(data);
+        }
+        v
+    }
+
+    fn chunk(clipboard: u8, sequence_number: u32, mark: Mark, data: &[u8]) -> Clipboard {
+        Clipboard {
+            clipboard,
+            sequence_number,
+            mark,
+            data: data.to_vec(),
+        }
+    }
+
+    fn transfer(
+        clipboard: u8,
+        sequence_number: u32,
+        data: &[u8],
+        chunk_size: usize,
+    ) -> Vec<Clipboard> {
+        let mut chunks = vec![chunk(
+            clipboard,
+            sequence_number,
+            Mark::Start,
+            data.len().to_string().as_bytes(),
+        )];
+        for part in data.chunks(chunk_size) {
+            chunks.push(chunk(clipboard, sequence_number, Mark::Chunk, part));
+        }
+        chunks.push(chunk(clipboard, sequence_number, Mark::End, b""));
+        chunks
+    }
+
+    #[test]
+    fn decode_formats() {
+        let data = marshal(&[
+            (FORMAT_TEXT, b"hello"),
+            (FORMAT_HTML, b"<b>hello</b>"),
+            (FORMAT_BITMAP, &[1, 2, 3]),
+            (7, b"ignored"),
+        ]);
+        assert_eq!(
+            decode(&data),
+            Some(ClipboardData {
+                text: Some("hello".to_string()),
+                html: Some("<b>hello</b>".to_string()),
+                bitmap: Some(vec![1, 2, 3]),
+            })
+        );
+    }
+
+    #[test]
+    fn decode_empty() {
+        let data = decode(&marshal(&[])).unwrap();
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let data = marshal(&[(FORMAT_TEXT, b"hello")]);
+        assert_eq!(decode(&data[..data.len() - 1]), None);
+        assert_eq!(decode(&[0, 0]), None);
+    }
+
+    #[test]
+    fn selection_ids() {
+        for selection in &Selection::ALL {
+            assert_eq!(Selection::from_id(selection.id()), Some(*selection));
+        }
+        assert_eq!(Selection::from_id(2), None);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let data = ClipboardData {
+            text: Some("hello".to_string()),
+            html: None,
+            bitmap: Some(vec![1, 2, 3]),
+        };
+        let encoded = encode(&data);
+        assert_eq!(
+            encoded,
+            marshal(&[(FORMAT_TEXT, b"hello"), (FORMAT_BITMAP, &[1, 2, 3])])
+        );
+        assert_eq!(decode(&encoded), Some(data));
+    }
+
+    #[test]
+    fn chunks_reassemble() {
+        let data = ClipboardData {
+            text: Some("x".repeat(CHUNK_SIZE * 2)),
+            ..ClipboardData::default()
+        };
+        let sent = chunks(0, 9, &encode(&data));
+        assert_eq!(sent.len(), 5);
+        let mut reassembler = Reassembler::new();
+        let mut update = None;
+        for chunk in sent {
+            update = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(
+            update,
+            Some(Update {
+                clipboard: 0,
+                sequence_number: 9,
+                data,
+                dropped: Vec::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn reassemble_chunks() {
+        let data = marshal(&[(FORMAT_TEXT, b"a longer piece of text")]);
+        let mut reassembler = Reassembler::new();
+        let mut chunks = transfer(0, 5, &data, 4);
+        let end = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk), Ok(None));
+        }
+        assert_eq!(
+            reassembler.push(end),
+            Ok(Some(Update {
+                clipboard: 0,
+                sequence_number: 5,
+                data: ClipboardData {
+                    text: Some("a longer piece of text".to_string()),
+                    ..ClipboardData::default()
+                },
+                dropped: Vec::new(),
+            }))
+        );
+    }
+
+    #[test]
+    fn reassemble_interleaved_clipboards() {
+        let clipboard = marshal(&[(FORMAT_TEXT, b"clipboard")]);
+        let selection = marshal(&[(FORMAT_TEXT, b"selection")]);
+        let mut reassembler = Reassembler::new();
+        let mut updates = Vec::new();
+        for (a, b) in transfer(0, 1, &clipboard, 8)
+            .into_iter()
+            .zip(transfer(1, 1, &selection, 8))
+        {
+            updates.extend(reassembler.push(a).unwrap());
+            updates.extend(reassembler.push(b).unwrap());
+        }
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].data.text, Some("clipboard".to_string()));
+        assert_eq!(updates[1].clipboard, 1);
+        assert_eq!(updates[1].data.text, Some("selection".to_string()));
+    }
+
+    #[test]
+    fn new_start_replaces_stale_transfer() {
+        let data = marshal(&[(FORMAT_TEXT, b"new")]);
+        let mut reassembler = Reassembler::new();
+        reassembler.push(chunk(0, 1, Mark::Start, b"100")).unwrap();
+        reassembler.push(chunk(0, 1, Mark::Chunk, b"old")).unwrap();
+        let mut update = None;
+        for chunk in transfer(0, 2, &data, 16) {
+            update = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(update.unwrap().data.text, Some("new".to_string()));
+        assert_eq!(
+            reassembler.push(chunk(0, 1, Mark::End, b"")),
+            Err(Error::UnexpectedChunk {
+                clipboard: 0,
+                sequence_number: 1
+            })
+        );
+    }
+
+    #[test]
+    fn chunk_without_start() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(
+            reassembler.push(chunk(0, 1, Mark::Chunk, b"data")),
+            Err(Error::UnexpectedChunk {
+                clipboard: 0,
+                sequence_number: 1
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_size() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(
+            reassembler.push(chunk(0, 1, Mark::Start, b"lots")),
+            Err(Error::InvalidSize {
+                clipboard: 0,
+                size: "lots".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let mut reassembler = Reassembler::new();
+        reassembler.push(chunk(0, 1, Mark::Start, b"2")).unwrap();
+        assert_eq!(
+            reassembler.push(chunk(0, 1, Mark::Chunk, b"abc")),
+            Err(Error::SizeMismatch {
+                clipboard: 0,
+                expected: 2,
+                actual: 3
+            })
+        );
+
+        reassembler.push(chunk(0, 2, Mark::Start, b"4")).unwrap();
+        reassembler.push(chunk(0, 2, Mark::Chunk, b"abc")).unwrap();
+        assert_eq!(
+            reassembler.push(chunk(0, 2, Mark::End, b"")),
+            Err(Error::SizeMismatch {
+                clipboard: 0,
+                expected: 4,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn legacy_clipboard() {
+        let mut reassembler = Reassembler::new();
+        let update = reassembler
+            .push_legacy(LegacyClipboard {
+                clipboard: 1,
+                sequence_number: 3,
+                data: marshal(&[(FORMAT_HTML, b"<i>x</i>")]),
+            })
+            .unwrap();
+        assert_eq!(update.clipboard, 1);
+        assert_eq!(update.data.html, Some("<i>x</i>".to_string()));
+    }
+
+    #[test]
+    fn filter_formats() {
+        let mut data = ClipboardData {
+            text: Some("text".to_string()),
+            html: Some("<b>text</b>".to_string()),
+            bitmap: Some(vec![0; 10]),
+        };
+        let filter = Filter {
+            formats: vec![Format::Text, Format::Bitmap],
+            max_bitmap_size: 9,
+            ..Filter::default()
+        };
+        assert_eq!(
+            filter.apply(&mut data),
+            vec![
+                Dropped {
+                    format: Format::Html,
+                    size: 11
+                },
+                Dropped {
+                    format: Format::Bitmap,
+                    size: 10
+                },
+            ]
+        );
+        assert_eq!(data.text, Some("text".to_string()));
+        assert!(data.html.is_none() && data.bitmap.is_none());
+    }
+
+    #[test]
+    fn filter_text_only() {
+        let filter = Filter {
+            text_only: true,
+            max_text_size: 100,
+            ..Filter::default()
+        };
+        assert!(filter.allows(Format::Text));
+        assert!(!filter.allows(Format::Html));
+        assert_eq!(filter.max_transfer_size(), 4 + 8 + 100);
+    }
+
+    #[test]
+    fn oversized_transfer_is_skipped() {
+        let filter = Filter {
+            text_only: true,
+            max_text_size: 30,
+            ..Filter::default()
+        };
+        let mut reassembler = Reassembler::with_filter(filter);
+        let data = marshal(&[(FORMAT_TEXT, "x".repeat(40).as_bytes())]);
+        let mut chunks = transfer(0, 1, &data, 4).into_iter();
+        assert_eq!(
+            reassembler.push(chunks.next().unwrap()),
+            Err(Error::TooLarge {
+                clipboard: 0,
+                size: data.len(),
+                limit: 42
+            })
+        );
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk), Ok(None));
+        }
+
+        let data = marshal(&[(FORMAT_TEXT, b"ok"), (FORMAT_HTML, b"<b>ok</b>")]);
+        let mut update = None;
+        for chunk in transfer(0, 2, &data, 4) {
+            update = reassembler.push(chunk).unwrap();
+        }
+        let update = update.unwrap();
+        assert_eq!(update.data.text, Some("ok".to_string()));
+        assert_eq!(
+            update.dropped,
+            vec![Dropped {
+                format: Format::Html,
+                size: 9
+            }]
+        );
+    }
+}
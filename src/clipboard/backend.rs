@@ -0,0 +1,413 @@
+use super::convert::{self, LOCAL_TYPES};
+use super::{ClipboardData, Selection};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::collections::HashMap;
+use std::env;
+use std::io::Write;
+use std::process::{Command, ExitStatus, Stdio};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not run {}: {}", program, source))]
+    Spawn {
+        program: &'static str,
+        source: std::io::Error,
+    },
+    #[snafu(display("Could not write clipboard to {}: {}", program, source))]
+    WriteClipboard {
+        program: &'static str,
+        source: std::io::Error,
+    },
+    #[snafu(display("{} failed: {}", program, status))]
+    CommandFailed {
+        program: &'static str,
+        status: ExitStatus,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Access to the local clipboard.
+pub trait ClipboardBackend: Send {
+    /// Replaces the local `selection` with `data`.
+    fn set(&mut self, selection: Selection, data: &ClipboardData) -> Result<()>;
+    /// Reads the local `selection`, `None` when it is empty.
+    fn get(&mut self, selection: Selection) -> Result<Option<ClipboardData>>;
+}
+
+/// Which clipboard backend to use, `auto` picks one from the session's
+/// environment.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Auto,
+    Wayland,
+    X11,
+    Memory,
+    None,
+}
+
+pub fn from_kind(kind: BackendKind) -> Box<dyn ClipboardBackend> {
+    match kind {
+        BackendKind::Auto if env::var_os("WAYLAND_DISPLAY").is_some() => Box::new(WlCopy),
+        BackendKind::Auto if env::var_os("DISPLAY").is_some() => Box::new(Xclip),
+        BackendKind::Auto | BackendKind::None => Box::new(NoBackend),
+        BackendKind::Wayland => Box::new(WlCopy),
+        BackendKind::X11 => Box::new(Xclip),
+        BackendKind::Memory => Box::new(MemoryBackend::default()),
+    }
+}
+
+/// Picks the format to offer locally. The command line tools can only offer
+/// a single MIME type, so plain text wins to keep pasting into terminals and
+/// editors working.
+fn preferred(data: &ClipboardData) -> Option<(&'static str, Vec<u8>)> {
+    convert::to_mime(data).into_iter().next()
+}
+
+/// Pipes `content` into `command` and waits for it to take over the
+/// selection.
+fn pipe_to(program: &'static str, mut command: Command, content: &[u8]) -> Result<()> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context(Spawn { program })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(content)
+            .context(WriteClipboard { program })?;
+    }
+    let status = child.wait().context(Spawn { program })?;
+    ensure!(status.success(), CommandFailed { program, status });
+    Ok(())
+}
+
+/// Reads what `command` prints. The tools fail when the clipboard is empty
+/// or does not hold the requested type, so a failure reads as nothing.
+fn read_from(program: &'static str, mut command: Command) -> Result<Option<Vec<u8>>> {
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context(Spawn { program })?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(output.stdout))
+}
+
+/// Picks the most preferred of the types listed one per line.
+fn pick_type(types: &[u8]) -> Option<&'static str> {
+    let types = String::from_utf8_lossy(types);
+    LOCAL_TYPES
+        .iter()
+        .copied()
+        .find(|wanted| types.lines().any(|offered| offered.trim() == *wanted))
+}
+
+/// Lists the types on offer with `list`, then reads and converts the most
+/// preferred one with the command `paste` builds.
+fn read_converted(
+    program: &'static str,
+    list: Command,
+    paste: impl Fn(&str) -> Command,
+) -> Result<Option<ClipboardData>> {
+    let mime = match read_from(program, list)?.as_deref().and_then(pick_type) {
+        Some(mime) => mime,
+        None => return Ok(None),
+    };
+    Ok(read_from(program, paste(mime))?.and_then(|content| convert::from_mime(mime, &content)))
+}
+
+/// Accesses the Wayland clipboard with `wl-copy` and `wl-paste` from
+/// wl-clipboard.
+#[derive(Debug)]
+pub struct WlCopy;
+
+impl WlCopy {
+    fn command(selection: Selection, mime: &str) -> Command {
+        let mut command = Command::new("wl-copy");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        command.arg("--type").arg(mime);
+        command
+    }
+
+    fn paste(selection: Selection) -> Command {
+        let mut command = Command::new("wl-paste");
+        if selection == Selection::Primary {
+            command.arg("--primary");
+        }
+        command
+    }
+
+    fn list_command(selection: Selection) -> Command {
+        let mut command = Self::paste(selection);
+        command.arg("--list-types");
+        command
+    }
+
+    fn paste_command(selection: Selection, mime: &str) -> Command {
+        let mut command = Self::paste(selection);
+        command.args(["--no-newline", "--type", mime]);
+        command
+    }
+}
+
+impl ClipboardBackend for WlCopy {
+    fn set(&mut self, selection: Selection, data: &ClipboardData) -> Result<()> {
+        match preferred(data) {
+            Some((mime, content)) => pipe_to("wl-copy", Self::command(selection, mime), &content),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&mut self, selection: Selection) -> Result<Option<ClipboardData>> {
+        read_converted("wl-paste", Self::list_command(selection), |mime| {
+            Self::paste_command(selection, mime)
+        })
+    }
+}
+
+/// Accesses the X11 clipboard with `xclip`.
+#[derive(Debug)]
+pub struct Xclip;
+
+impl Xclip {
+    fn selection(selection: Selection) -> &'static str {
+        match selection {
+            Selection::Clipboard => "clipboard",
+            Selection::Primary => "primary",
+        }
+    }
+
+    fn command(selection: Selection, mime: &str) -> Command {
+        let mut command = Command::new("xclip");
+        command
+            .args(["-selection", Self::selection(selection), "-target", mime])
+            .arg("-in");
+        command
+    }
+
+    fn paste_command(selection: Selection, target: &str) -> Command {
+        let mut command = Command::new("xclip");
+        command
+            .args(["-selection", Self::selection(selection), "-target", target])
+            .arg("-out");
+        command
+    }
+}
+
+impl ClipboardBackend for Xclip {
+    fn set(&mut self, selection: Selection, data: &ClipboardData) -> Result<()> {
+        match preferred(data) {
+            Some((mime, content)) => pipe_to("xclip", Self::command(selection, mime), &content),
+            None => Ok(()),
+        }
+    }
+
+    fn get(&mut self, selection: Selection) -> Result<Option<ClipboardData>> {
+        read_converted(
+            "xclip",
+            Self::paste_command(selection, "TARGETS"),
+            |target| Self::paste_command(selection, target),
+        )
+    }
+}
+
+/// Keeps the clipboard in memory, for tests and for running without a
+/// graphical session.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    data: HashMap<Selection, ClipboardData>,
+}
+
+impl MemoryBackend {
+    pub fn data(&self, selection: Selection) -> Option<&ClipboardData> {
+        self.data.get(&selection)
+    }
+}
+
+impl ClipboardBackend for MemoryBackend {
+    fn set(&mut self, selection: Selection, data: &ClipboardData) -> Result<()> {
+        self.data.insert(selection, data.clone());
+        Ok(())
+    }
+
+    fn get(&mut self, selection: Selection) -> Result<Option<ClipboardData>> {
+        Ok(self.data.get(&selection).cloned())
+    }
+}
+
+/// Drops received clipboards and never has anything to share.
+#[derive(Debug)]
+pub struct NoBackend;
+
+impl ClipboardBackend for NoBackend {
+    fn set(&mut self, _: Selection, _: &ClipboardData) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&mut self, _: Selection) -> Result<Option<ClipboardData>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&str> {
+        command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn prefers_text() {
+        let data = ClipboardData {
+            text: Some("text".to_string()),
+            html: Some("<p>text</p>".to_string()),
+            bitmap: None,
+        };
+        assert_eq!(
+            preferred(&data),
+            Some(("text/plain;charset=utf-8", b"text".to_vec()))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_html() {
+        let data = ClipboardData {
+            html: Some("<p>text</p>".to_string()),
+            ..ClipboardData::default()
+        };
+        assert_eq!(
+            preferred(&data),
+            Some(("text/html", b"<p>text</p>".to_vec()))
+        );
+        assert_eq!(preferred(&ClipboardData::default()), None);
+    }
+
+    #[test]
+    fn commands() {
+        let wl_copy = WlCopy::command(Selection::Clipboard, "text/html");
+        assert_eq!(wl_copy.get_program(), "wl-copy");
+        assert_eq!(args(&wl_copy), ["--type", "text/html"]);
+        let xclip = Xclip::command(Selection::Clipboard, "text/html");
+        assert_eq!(xclip.get_program(), "xclip");
+        assert_eq!(
+            args(&xclip),
+            ["-selection", "clipboard", "-target", "text/html", "-in"]
+        );
+        assert_eq!(
+            args(&WlCopy::list_command(Selection::Clipboard)),
+            ["--list-types"]
+        );
+        assert_eq!(
+            args(&WlCopy::paste_command(Selection::Clipboard, "image/png")),
+            ["--no-newline", "--type", "image/png"]
+        );
+        assert_eq!(
+            args(&Xclip::paste_command(Selection::Clipboard, "UTF8_STRING")),
+            ["-selection", "clipboard", "-target", "UTF8_STRING", "-out"]
+        );
+    }
+
+    #[test]
+    fn primary_commands() {
+        assert_eq!(
+            args(&WlCopy::command(Selection::Primary, "text/html")),
+            ["--primary", "--type", "text/html"]
+        );
+        assert_eq!(
+            args(&WlCopy::paste_command(Selection::Primary, "text/plain")),
+            ["--primary", "--no-newline", "--type", "text/plain"]
+        );
+        assert_eq!(
+            args(&Xclip::command(Selection::Primary, "text/html")),
+            ["-selection", "primary", "-target", "text/html", "-in"]
+        );
+        assert_eq!(
+            args(&Xclip::paste_command(Selection::Primary, "UTF8_STRING")),
+            ["-selection", "primary", "-target", "UTF8_STRING", "-out"]
+        );
+    }
+
+    #[test]
+    fn memory_backend() {
+        let data = ClipboardData {
+            text: Some("copied".to_string()),
+            ..ClipboardData::default()
+        };
+        let mut backend = MemoryBackend::default();
+        assert_eq!(backend.data(Selection::Clipboard), None);
+        assert_eq!(backend.get(Selection::Clipboard).unwrap(), None);
+        backend.set(Selection::Clipboard, &data).unwrap();
+        assert_eq!(backend.data(Selection::Clipboard), Some(&data));
+        assert_eq!(backend.get(Selection::Clipboard).unwrap(), Some(data));
+        assert_eq!(backend.get(Selection::Primary).unwrap(), None);
+    }
+
+    #[test]
+    fn pipe_to_reports_failure() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "cat > /dev/null; exit 3"]);
+        match pipe_to("sh", command, b"data") {
+            Err(Error::CommandFailed { program, .. }) => assert_eq!(program, "sh"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn read_from_output() {
+        let mut command = Command::new("printf");
+        command.arg("copied");
+        assert_eq!(
+            read_from("printf", command).unwrap(),
+            Some(b"copied".to_vec())
+        );
+        assert_eq!(read_from("false", Command::new("false")).unwrap(), None);
+    }
+
+    #[test]
+    fn picks_preferred_type() {
+        assert_eq!(
+            pick_type(b"TARGETS\nimage/png\ntext/html\nUTF8_STRING\n"),
+            Some("UTF8_STRING")
+        );
+        assert_eq!(pick_type(b"image/bmp\nimage/png"), Some("image/png"));
+        assert_eq!(pick_type(b"application/x-unknown"), None);
+    }
+
+    #[test]
+    fn read_converted_html() {
+        let list = |types: &str| {
+            let mut command = Command::new("printf");
+            command.arg(types);
+            command
+        };
+        let paste = |mime: &str| {
+            assert_eq!(mime, "text/html");
+            let mut command = Command::new("printf");
+            command.arg("<b>hi</b>");
+            command
+        };
+        assert_eq!(
+            read_converted("printf", list("image/x-unknown\ntext/html"), paste)
+                .unwrap()
+                .and_then(|data| data.html),
+            Some("<b>hi</b>".to_string())
+        );
+        assert_eq!(
+            read_converted("printf", list("image/x-unknown"), paste).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use super::ClipboardData;
+use std::convert::TryInto;
+
+pub const TEXT: &str = "text/plain;charset=utf-8";
+pub const HTML: &str = "text/html";
+pub const PNG: &str = "image/png";
+pub const BMP: &str = "image/bmp";
+
+/// Types read from the local clipboard, in order of preference. X11 names
+/// UTF-8 text `UTF8_STRING` rather than by its MIME type.
+pub const LOCAL_TYPES: [&str; 6] = [TEXT, "UTF8_STRING", "text/plain", HTML, PNG, BMP];
+
+/// Size of `BITMAPFILEHEADER`, which a DIB lacks.
+const FILE_HEADER_SIZE: usize = 14;
+/// Size of `BITMAPINFOHEADER`, the smallest header we accept.
+const INFO_HEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// Converts received clipboard contents into the MIME types offered
+/// locally, most preferred first.
+pub fn to_mime(data: &ClipboardData) -> Vec<(&'static str, Vec<u8>)> {
+    let mut types = Vec::new();
+    if let Some(text) = &data.text {
+        types.push((TEXT, text.as_bytes().to_vec()));
+    }
+    if let Some(html) = &data.html {
+        types.push((HTML, html_fragment(html).as_bytes().to_vec()));
+    }
+    if let Some(bitmap) = &data.bitmap {
+        if let Some(png) = dib_to_png(bitmap) {
+            types.push((PNG, png));
+        }
+        if let Some(bmp) = dib_to_bmp(bitmap) {
+            types.push((BMP, bmp));
+        }
+    }
+    types
+}
+
+/// Converts local clipboard `content` of type `mime` into Barrier's formats.
+pub fn from_mime(mime: &str, content: &[u8]) -> Option<ClipboardData> {
+    let mut data = ClipboardData::default();
+    match mime {
+        TEXT | "UTF8_STRING" | "text/plain" => {
+            data.text = Some(String::from_utf8_lossy(content).into_owned())
+        }
+        HTML => data.html = Some(decode_html(content)),
+        PNG => data.bitmap = Some(png_to_dib(content)?),
+        BMP => data.bitmap = Some(bmp_to_dib(content)?),
+        _ => return None,
+    }
+    Some(data)
+}
+
+/// Windows servers may send HTML in the `CF_HTML` format, a header of
+/// offsets followed by a whole document. Only the fragment marked by the
+/// header is the copied content.
+fn html_fragment(html: &str) -> &str {
+    if !html.starts_with("Version:") {
+        return html;
+    }
+    let offset = |key: &str| {
+        html.lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|value| value.trim().parse::<usize>().ok())
+    };
+    match (offset("StartFragment:"), offset("EndFragment:")) {
+        (Some(start), Some(end)) => html.get(start..end).unwrap_or(html),
+        _ => html,
+    }
+}
+
+/// Browsers put UTF-16 `text/html` on the clipboard, marked by a byte order
+/// mark. Barrier expects UTF-8.
+fn decode_html(content: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], from: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|pair| from([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    match content {
+        [0xff, 0xfe, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xfe, 0xff, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        [0xef, 0xbb, 0xbf, rest @ ..] => String::from_utf8_lossy(rest).into_owned(),
+        _ => String::from_utf8_lossy(content).into_owned(),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn i32_at(data: &[u8], offset: usize) -> Option<i32> {
+    Some(i32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Size of the colour masks and palette between a DIB's header and its
+/// pixels.
+fn dib_extra_size(dib: &[u8]) -> Option<usize> {
+    let header_size = u32_at(dib, 0)? as usize;
+    let bit_count = u16_at(dib, 14)?;
+    let compression = u32_at(dib, 16)?;
+    let colors_used = u32_at(dib, 32)? as usize;
+    let masks = if compression == BI_BITFIELDS && header_size == INFO_HEADER_SIZE {
+        12
+    } else {
+        0
+    };
+    let palette = match (bit_count, colors_used) {
+        (1..=8, 0) => 1 << bit_count,
+        (_, colors_used) => colors_used,
+    };
+    Some(masks + palette * 4)
+}
+
+/// Adds the file header that turns a DIB into a BMP file.
+pub fn dib_to_bmp(dib: &[u8]) -> Option<Vec<u8>> {
+    let header_size = u32_at(dib, 0)? as usize;
+    if header_size < INFO_HEADER_SIZE {
+        return None;
+    }
+    let pixels = FILE_HEADER_SIZE + header_size + dib_extra_size(dib)?;
+    let mut bmp = Vec::with_capacity(FILE_HEADER_SIZE + dib.len());
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&((FILE_HEADER_SIZE + dib.len()) as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&(pixels as u32).to_le_bytes());
+    bmp.extend_from_slice(dib);
+    Some(bmp)
+}
+
+/// Strips the file header off a BMP file.
+pub fn bmp_to_dib(bmp: &[u8]) -> Option<Vec<u8>> {
+    if !bmp.starts_with(b"BM") || bmp.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE {
+        return None;
+    }
+    Some(bmp[FILE_HEADER_SIZE..].to_vec())
+}
+
+/// Reads a channel selected by `mask` from a 32 bit pixel.
+fn channel(pixel: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    ((pixel & mask) >> mask.trailing_zeros()) as u8
+}
+
+/// Decodes an uncompressed 24 or 32 bit DIB into top down RGBA rows.
+fn dib_pixels(dib: &[u8]) -> Option<(u32, u32, Vec<u8>)> {
+    let header_size = u32_at(dib, 0)? as usize;
+    let width = i32_at(dib, 4)?;
+    let height = i32_at(dib, 8)?;
+    let bit_count = u16_at(dib, 14)?;
+    let compression = u32_at(dib, 16)?;
+    if header_size < INFO_HEADER_SIZE || width <= 0 || height == 0 {
+        return None;
+    }
+    let (red, green, blue, alpha) = match (bit_count, compression) {
+        (24, BI_RGB) | (32, BI_RGB) => (0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0),
+        (32, BI_BITFIELDS) => {
+            // The masks follow a plain info header and are part of newer
+            // ones, where the alpha mask comes right after them.
+            let alpha = if header_size > INFO_HEADER_SIZE {
+                u32_at(dib, 52)?
+            } else {
+                0
+            };
+            (u32_at(dib, 40)?, u32_at(dib, 44)?, u32_at(dib, 48)?, alpha)
+        }
+        _ => return None,
+    };
+    let (width, rows) = (width as usize, height.unsigned_abs() as usize);
+    let bytes_per_pixel = bit_count as usize / 8;
+    let stride = (width * bit_count as usize).div_ceil(32) * 4;
+    let offset = header_size + dib_extra_size(dib)?;
+    let pixels = dib.get(offset..offset.checked_add(stride.checked_mul(rows)?)?)?;
+
+    let mut rgba = Vec::with_capacity(width * rows * 4);
+    for row in 0..rows {
+        // Positive heights are stored bottom up.
+        let row = if height > 0 { rows - 1 - row } else { row };
+        let row = &pixels[row * stride..row * stride + width * bytes_per_pixel];
+        for pixel in row.chunks_exact(bytes_per_pixel) {
+            let mut bytes = [0; 4];
+            bytes[..bytes_per_pixel].copy_from_slice(pixel);
+            let pixel = u32::from_le_bytes(bytes);
+            rgba.push(channel(pixel, red));
+            rgba.push(channel(pixel, green));
+            rgba.push(channel(pixel, blue));
+            rgba.push(if alpha == 0 {
+                0xff
+            } else {
+                channel(pixel, alpha)
+            });
+        }
+    }
+    Some((width as u32, rows as u32, rgba))
+}
+
+pub fn dib_to_png(dib: &[u8]) -> Option<Vec<u8>> {
+    let (width, height, rgba) = dib_pixels(dib)?;
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().ok()?.write_image_data(&rgba).ok()?;
+    Some(png)
+}
+
+/// Decodes a PNG into a 32 bit bottom up DIB.
+pub fn png_to_dib(content: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = png::Decoder::new(content);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().ok()?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).ok()?;
+    let samples = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => return None,
+    };
+    let (width, height) = (info.width as usize, info.height as usize);
+
+    let mut dib = Vec::with_capacity(INFO_HEADER_SIZE + width * height * 4);
+    dib.extend_from_slice(&(INFO_HEADER_SIZE as u32).to_le_bytes());
+    dib.extend_from_slice(&(width as i32).to_le_bytes());
+    dib.extend_from_slice(&(height as i32).to_le_bytes());
+    dib.extend_from_slice(&1u16.to_le_bytes());
+    dib.extend_from_slice(&32u16.to_le_bytes());
+    dib.extend_from_slice(&BI_RGB.to_le_bytes());
+    dib.extend_from_slice(&((width * height * 4) as u32).to_le_bytes());
+    // 72 DPI in pixels per metre, then no palette.
+    dib.extend_from_slice(&2835u32.to_le_bytes());
+    dib.extend_from_slice(&2835u32.to_le_bytes());
+    dib.extend_from_slice(&[0; 8]);
+    for row in buffer.chunks_exact(info.line_size).take(height).rev() {
+        for pixel in row.chunks_exact(samples).take(width) {
+            let (r, g, b, a) = match *pixel {
+                [gray] => (gray, gray, gray, 0xff),
+                [gray, a] => (gray, gray, gray, a),
+                [r, g, b] => (r, g, b, 0xff),
+                [r, g, b, a] => (r, g, b, a),
+                _ => unreachable!(),
+            };
+            dib.extend_from_slice(&[b, g, r, a]);
+        }
+    }
+    Some(dib)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    // 2x2 24 bit bottom up DIB: red and white on top, blue and green below.
+    const DIB: [u8; 56] = hex!(
+        "28 00 00 00 02 00 00 00 02 00 00 00 01 00 18 00
+         00 00 00 00 10 00 00 00 13 0b 00 00 13 0b 00 00
+         00 00 00 00 00 00 00 00
+         ff 00 00 00 ff 00 00 00
+         00 00 ff ff ff ff 00 00"
+    );
+
+    const RGBA: [u8; 16] = hex!("ff 00 00 ff ff ff ff ff 00 00 ff ff 00 ff 00 ff");
+
+    fn decode_png(content: &[u8]) -> (u32, u32, Vec<u8>) {
+        let (info, mut reader) = png::Decoder::new(content).read_info().unwrap();
+        let mut buffer = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buffer).unwrap();
+        assert_eq!(info.color_type, png::ColorType::RGBA);
+        (info.width, info.height, buffer)
+    }
+
+    #[test]
+    fn dib_pixels_bottom_up() {
+        assert_eq!(dib_pixels(&DIB), Some((2, 2, RGBA.to_vec())));
+    }
+
+    #[test]
+    fn dib_pixels_top_down_bitfields() {
+        // 1x2 32 bit top down DIB with RGBA masks.
+        let dib = hex!(
+            "28 00 00 00 01 00 00 00 fe ff ff ff 01 00 20 00
+             03 00 00 00 08 00 00 00 00 00 00 00 00 00 00 00
+             00 00 00 00 00 00 00 00
+             ff 00 00 00 00 ff 00 00 00 00 ff 00
+             11 22 33 44 55 66 77 88"
+        );
+        assert_eq!(
+            dib_pixels(&dib),
+            Some((1, 2, hex!("11 22 33 ff 55 66 77 ff").to_vec()))
+        );
+    }
+
+    #[test]
+    fn dib_pixels_truncated() {
+        assert_eq!(dib_pixels(&DIB[..DIB.len() - 1]), None);
+        assert_eq!(dib_pixels(&DIB[..20]), None);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let png = dib_to_png(&DIB).unwrap();
+        assert_eq!(decode_png(&png), (2, 2, RGBA.to_vec()));
+        let dib = png_to_dib(&png).unwrap();
+        assert_eq!(dib.len(), INFO_HEADER_SIZE + 16);
+        assert_eq!(dib_pixels(&dib), Some((2, 2, RGBA.to_vec())));
+        assert_eq!(png_to_dib(b"not a png"), None);
+    }
+
+    #[test]
+    fn bmp_file_header() {
+        let bmp = dib_to_bmp(&DIB).unwrap();
+        assert_eq!(
+            &bmp[..14],
+            &hex!("42 4d 46 00 00 00 00 00 00 00 36 00 00 00")
+        );
+        assert_eq!(bmp_to_dib(&bmp), Some(DIB.to_vec()));
+        assert_eq!(bmp_to_dib(&DIB), None);
+    }
+
+    #[test]
+    fn cf_html_fragment() {
+        let html = "Version:0.9\r\nStartHTML:0000000105\r\nEndHTML:0000000182\r\n\
+                    StartFragment:0000000139\r\nEndFragment:0000000148\r\n\
+                    <html><body>\r\n<!--StartFragment--><b>hi</b><!--EndFragment-->\r\n\
+                    </body></html>";
+        assert_eq!(html_fragment(html), "<b>hi</b>");
+        assert_eq!(html_fragment("<b>hi</b>"), "<b>hi</b>");
+    }
+
+    #[test]
+    fn html_encodings() {
+        assert_eq!(decode_html(&hex!("ff fe 3c 00 62 00 3e 00")), "<b>");
+        assert_eq!(decode_html(&hex!("fe ff 00 3c 00 62 00 3e")), "<b>");
+        assert_eq!(decode_html(&hex!("ef bb bf 3c 62 3e")), "<b>");
+        assert_eq!(decode_html(b"<b>"), "<b>");
+    }
+
+    #[test]
+    fn mime_types() {
+        let data = ClipboardData {
+            text: Some("hi".to_string()),
+            html: Some("<b>hi</b>".to_string()),
+            bitmap: Some(DIB.to_vec()),
+        };
+        let types: Vec<_> = to_mime(&data).into_iter().map(|(mime, _)| mime).collect();
+        assert_eq!(types, [TEXT, HTML, PNG, BMP]);
+
+        assert_eq!(
+            from_mime("UTF8_STRING", b"hi").unwrap().text,
+            Some("hi".to_string())
+        );
+        assert_eq!(
+            from_mime(BMP, &dib_to_bmp(&DIB).unwrap()).unwrap().bitmap,
+            Some(DIB.to_vec())
+        );
+        assert_eq!(from_mime("application/x-unknown", b""), None);
+    }
+}
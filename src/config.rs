@@ -0,0 +1,399 @@
+use crate::clipboard::backend::BackendKind;
+use crate::clipboard::Filter;
+use crate::language::Languages;
+use crate::proxy::Proxy;
+use crate::screen::{Output, Transform};
+use crate::screensaver::Screensaver;
+use crate::transport::{self, Transport, TransportKind};
+use nix::unistd::gethostname;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not get XDG base directory: {}", source))]
+    ConfigDir { source: xdg::BaseDirectoriesError },
+    #[snafu(display("Could not get config file"))]
+    ConfigFile {},
+    #[snafu(display("Could not merge config: {}", source))]
+    MergeConfig { source: config::ConfigError },
+    #[snafu(display("Could not parse config: {}", source))]
+    DeserializeConfig { source: config::ConfigError },
+    #[snafu(display("Invalid screen configuration: {}", source))]
+    InvalidScreen { source: crate::screen::Error },
+    #[snafu(display("Invalid server transport: {}", source))]
+    InvalidTransport { source: transport::Error },
+    #[snafu(display("No server configured"))]
+    NoServer {},
+    #[snafu(display("abs_x_maximum and abs_y_maximum must be set together"))]
+    PartialAbsMaximum {},
+    #[snafu(display("Invalid screen name {:?}: {}", name, reason))]
+    InvalidName { name: String, reason: &'static str },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Longest screen name the server will accept.
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    /// Screen name announced to the server, defaults to the hostname.
+    #[serde(default = "default_name")]
+    pub name: String,
+    pub server: Option<ConfigServer>,
+    /// Further servers to fail over to.
+    #[serde(default)]
+    pub servers: Vec<ConfigServer>,
+    #[serde(default)]
+    pub failover: ConfigFailover,
+    #[serde(default)]
+    pub screen: ConfigScreen,
+    #[serde(default)]
+    pub clipboard: ConfigClipboard,
+    #[serde(default)]
+    pub files: ConfigFiles,
+    #[serde(default)]
+    pub screensaver: Screensaver,
+    #[serde(default)]
+    pub languages: Languages,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigServer {
+    /// `host[:port]`, `auto` for the first server announced over mDNS, or
+    /// the name a server announces itself with. `auto` and `.local` hosts
+    /// need a direct `tcp` connection, proxies resolve names themselves.
+    pub address: String,
+    /// Lower is preferred, servers of equal priority keep their order.
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// Socket path for the `unix` transport.
+    pub socket: Option<PathBuf>,
+    /// Command for the `proxy` transport, `%h` and `%p` are replaced with
+    /// the host and port of `address`.
+    pub proxy_command: Option<String>,
+    /// SOCKS5 or HTTP proxy for the `tcp` transport.
+    pub proxy: Option<Proxy>,
+    /// Seconds to keep retrying while the server reports our name as busy.
+    #[serde(default = "default_busy_retry_period")]
+    pub busy_retry_period: u64,
+    /// Seconds to resolve and connect to the server, including any proxy
+    /// handshake, before trying again.
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigFailover {
+    /// Whether to leave a backup server once a preferred one is reachable
+    /// again.
+    #[serde(default)]
+    pub failback: bool,
+    /// Seconds between checks for a preferred server.
+    #[serde(default = "default_failback_interval")]
+    pub failback_interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigScreen {
+    #[serde(default = "default_outputs")]
+    pub outputs: Vec<Output>,
+    pub abs_x_maximum: Option<i32>,
+    pub abs_y_maximum: Option<i32>,
+    #[serde(default)]
+    pub jump_zone: i16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigClipboard {
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// Whether to share the primary selection used for middle click paste.
+    #[serde(default = "default_primary")]
+    pub primary: bool,
+    #[serde(default)]
+    pub filter: Filter,
+}
+
+impl Default for ConfigScreen {
+    fn default() -> Self {
+        Self {
+            outputs: default_outputs(),
+            abs_x_maximum: None,
+            abs_y_maximum: None,
+            jump_zone: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigFiles {
+    /// Where files dragged onto our screen are saved.
+    #[serde(default = "default_download_dir")]
+    pub download_dir: PathBuf,
+}
+
+impl Default for ConfigFiles {
+    fn default() -> Self {
+        Self {
+            download_dir: default_download_dir(),
+        }
+    }
+}
+
+impl Default for ConfigClipboard {
+    fn default() -> Self {
+        Self {
+            backend: BackendKind::default(),
+            primary: default_primary(),
+            filter: Filter::default(),
+        }
+    }
+}
+
+impl Default for ConfigFailover {
+    fn default() -> Self {
+        Self {
+            failback: false,
+            failback_interval: default_failback_interval(),
+        }
+    }
+}
+
+fn default_outputs() -> Vec<Output> {
+    vec![Output {
+        x: 0,
+        y: 0,
+        width: 1920,
+        height: 1080,
+        scale: 1.0,
+    }]
+}
+
+fn default_primary() -> bool {
+    true
+}
+
+fn default_download_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_DOWNLOAD_DIR") {
+        return PathBuf::from(dir);
+    }
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join("Downloads"),
+        None => env::temp_dir(),
+    }
+}
+
+fn default_busy_retry_period() -> u64 {
+    60
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_failback_interval() -> u64 {
+    30
+}
+
+fn default_name() -> String {
+    let mut buffer = [0u8; MAX_NAME_LENGTH + 1];
+    gethostname(&mut buffer)
+        .ok()
+        .and_then(|name| name.to_str().ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("barrier-rust")
+        .to_string()
+}
+
+impl ConfigServer {
+    pub fn transport(&self) -> Result<Transport> {
+        Transport::new(
+            self.transport,
+            &self.address,
+            self.socket.as_deref(),
+            self.proxy_command.as_deref(),
+            self.proxy.as_ref(),
+            Duration::from_secs(self.connect_timeout),
+        )
+        .context(InvalidTransport {})
+    }
+}
+
+impl ConfigScreen {
+    pub fn transform(&self) -> Result<Transform> {
+        let abs_maximum = match (self.abs_x_maximum, self.abs_y_maximum) {
+            (Some(x), Some(y)) => Some((x, y)),
+            (None, None) => None,
+            _ => return PartialAbsMaximum {}.fail(),
+        };
+        Transform::new(&self.outputs, abs_maximum).context(InvalidScreen {})
+    }
+}
+
+impl Config {
+    /// Loads `config.toml` from the XDG config directories, overridden by
+    /// `BARRIER_RUST_*` environment variables.
+    pub fn load() -> Result<Self> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("barrier-rust").context(ConfigDir {})?;
+        let config_path = xdg_dirs
+            .find_config_file("config.toml")
+            .context(ConfigFile {})?;
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from(config_path))
+            .context(MergeConfig {})?
+            .merge(config::Environment::with_prefix("BARRIER_RUST"))
+            .context(MergeConfig {})?;
+        let config = settings
+            .try_into::<Config>()
+            .context(DeserializeConfig {})?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Every configured server, most preferred first.
+    pub fn servers(&self) -> Vec<&ConfigServer> {
+        let mut servers: Vec<_> = self.server.iter().chain(&self.servers).collect();
+        servers.sort_by_key(|server| server.priority);
+        servers
+    }
+
+    /// Whether a server is reached over our stdin and stdout.
+    pub fn uses_stdio(&self) -> bool {
+        self.servers()
+            .iter()
+            .any(|server| server.transport == TransportKind::Stdio)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_name(&self.name)?;
+        let servers = self.servers();
+        ensure!(!servers.is_empty(), NoServer {});
+        for server in servers {
+            server.transport()?;
+        }
+        self.screen.transform()?;
+        Ok(())
+    }
+}
+
+/// Checks that `name` is usable as a screen name: hostname characters plus
+/// underscores, not starting or ending with a separator.
+pub fn validate_name(name: &str) -> Result<()> {
+    let invalid = |reason| InvalidName {
+        name: name.to_string(),
+        reason,
+    };
+    ensure!(!name.is_empty(), invalid("name is empty"));
+    ensure!(name.len() <= MAX_NAME_LENGTH, invalid("name is too long"));
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'),
+        invalid("only letters, digits, '-', '_' and '.' are allowed")
+    );
+    let separators = &['-', '.'][..];
+    ensure!(
+        !name.starts_with(separators) && !name.ends_with(separators),
+        invalid("name can not start or end with '-' or '.'")
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from_str(toml, config::FileFormat::Toml))
+            .unwrap();
+        settings.try_into().unwrap()
+    }
+
+    #[test]
+    fn servers_by_priority() {
+        let config = parse(
+            r#"
+            [server]
+            address = "primary"
+
+            [[servers]]
+            address = "fallback"
+            priority = 2
+
+            [[servers]]
+            address = "backup"
+            priority = 1
+
+            [[servers]]
+            address = "standby"
+            priority = 1
+            "#,
+        );
+        let addresses: Vec<_> = config
+            .servers()
+            .iter()
+            .map(|server| server.address.as_str())
+            .collect();
+        assert_eq!(addresses, ["primary", "backup", "standby", "fallback"]);
+        assert!(!config.failover.failback);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn needs_a_server() {
+        match parse("name = \"desk\"").validate() {
+            Err(Error::NoServer {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn abs_maximum_needs_both_axes() {
+        let config = parse(
+            r#"
+            name = "desk"
+
+            [server]
+            address = "primary"
+
+            [screen]
+            abs_x_maximum = 32767
+            "#,
+        );
+        match config.validate() {
+            Err(Error::PartialAbsMaximum {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn valid_names() {
+        for name in &["brendan-nom", "desk_2", "laptop.example.com", "a"] {
+            assert!(validate_name(name).is_ok(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_names() {
+        let too_long = "a".repeat(MAX_NAME_LENGTH + 1);
+        for name in &[
+            "",
+            "has space",
+            "ümlaut",
+            "-leading",
+            "trailing.",
+            &too_long,
+        ] {
+            assert!(validate_name(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use crate::mdns::{self, Mdns};
+use crate::transport::split_host_port;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// How long an attempt gets before the next address is tried alongside it,
+/// as recommended by RFC 8305.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Idle time before the kernel starts probing a silent connection.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Address that picks the first server announced over mDNS.
+pub const AUTO: &str = "auto";
+
+/// Resolves every address of `address`, using `default_port` when it names
+/// none.
+pub async fn resolve(address: &str, default_port: u16) -> io::Result<Vec<SocketAddr>> {
+    let dns = |host: String, port| async move { Ok(lookup_host((host, port)).await?.collect()) };
+    resolve_with(&Mdns::default(), dns, address, default_port).await
+}
+
+/// Like `resolve`, also finding servers through `mdns`: `auto` picks the
+/// first one announced, `.local` hosts are asked for over mDNS, and a
+/// single label `dns` does not know may be the name a server announces.
+pub async fn resolve_with<F, Fut>(
+    mdns: &Mdns,
+    dns: F,
+    address: &str,
+    default_port: u16,
+) -> io::Result<Vec<SocketAddr>>
+where
+    F: FnOnce(String, u16) -> Fut,
+    Fut: Future<Output = io::Result<Vec<SocketAddr>>>,
+{
+    if address.eq_ignore_ascii_case(AUTO) {
+        let services = mdns.browse().await?;
+        return match services.first() {
+            Some(service) => Ok(service.socket_addrs()),
+            None => Err(not_found("no Barrier server announced itself")),
+        };
+    }
+    let (host, port) = split_host_port(address);
+    let explicit_port = match port {
+        Some(port) => Some(
+            port.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port number"))?,
+        ),
+        None => None,
+    };
+    let port = explicit_port.unwrap_or(default_port);
+    if mdns::is_local(host) {
+        let addresses = mdns.lookup_host(host).await?;
+        if addresses.is_empty() {
+            return Err(not_found("no answer over mDNS"));
+        }
+        return Ok(addresses
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect());
+    }
+    match dns(host.to_string(), port).await {
+        Ok(addresses) => Ok(addresses),
+        Err(e) if explicit_port.is_none() && !host.contains(['.', ':']) => {
+            let services = mdns.browse().await?;
+            match services
+                .iter()
+                .find(|service| service.name.eq_ignore_ascii_case(host))
+            {
+                Some(service) => Ok(service.socket_addrs()),
+                None => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn not_found(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, reason)
+}
+
+/// Alternates between address families, starting with the family of the
+/// first address, so one unreachable family costs a single attempt delay.
+pub fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addresses.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_v6);
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Resolves `address` and connects to the first of its addresses to answer
+/// before `deadline`, with `TCP_NODELAY` and keepalive set.
+pub async fn connect(address: &str, default_port: u16, deadline: Instant) -> io::Result<TcpStream> {
+    let addresses = match tokio::time::timeout_at(deadline, resolve(address, default_port)).await {
+        Ok(addresses) => addresses?,
+        Err(_) => return Err(timed_out()),
+    };
+    let stream = race(
+        interleave(addresses),
+        TcpStream::connect,
+        ATTEMPT_DELAY,
+        deadline,
+    )
+    .await?;
+    stream.set_nodelay(true)?;
+    SockRef::from(&stream).set_tcp_keepalive(
+        &TcpKeepalive::new()
+            .with_time(KEEPALIVE_TIME)
+            .with_interval(KEEPALIVE_INTERVAL),
+    )?;
+    Ok(stream)
+}
+
+/// Starts an attempt for each address in turn, the next one once the
+/// previous failed or `delay` passed, and returns the first to succeed.
+/// Attempts still running are dropped.
+pub async fn race<F, Fut, T>(
+    addresses: Vec<SocketAddr>,
+    attempt: F,
+    delay: Duration,
+    deadline: Instant,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut pending = VecDeque::from(addresses);
+    let (sender, mut results) = mpsc::unbounded_channel();
+    let mut tasks = Vec::new();
+    let mut running = 0;
+    let mut last_error = None;
+    let mut start_next = |running: &mut usize| {
+        if let Some(address) = pending.pop_front() {
+            let sender = sender.clone();
+            let future = attempt(address);
+            tasks.push(tokio::spawn(async move {
+                let _ = sender.send(future.await);
+            }));
+            *running += 1;
+        }
+        !pending.is_empty()
+    };
+    let mut more = start_next(&mut running);
+    let result = loop {
+        if running == 0 {
+            break Err(last_error.unwrap_or_else(|| not_found("no addresses to connect to")));
+        }
+        tokio::select! {
+            Some(result) = results.recv() => {
+                running -= 1;
+                match result {
+                    Ok(stream) => break Ok(stream),
+                    Err(e) => {
+                        last_error = Some(e);
+                        more = start_next(&mut running);
+                    }
+                }
+            }
+            _ = sleep(delay), if more => more = start_next(&mut running),
+            _ = sleep_until(deadline) => break Err(timed_out()),
+        }
+    };
+    for task in tasks {
+        task.abort();
+    }
+    result
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "connection timed out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addresses(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn default_port() {
+        assert_eq!(
+            resolve("127.0.0.1", 24800).await.unwrap(),
+            addresses(&["127.0.0.1:24800"])
+        );
+        assert_eq!(
+            resolve("[::1]:24801", 24800).await.unwrap(),
+            addresses(&["[::1]:24801"])
+        );
+        assert!(resolve("127.0.0.1:port", 24800).await.is_err());
+    }
+
+    #[test]
+    fn families_alternate() {
+        assert_eq!(
+            interleave(addresses(&[
+                "[::1]:1",
+                "[::2]:1",
+                "[::3]:1",
+                "10.0.0.1:1",
+                "10.0.0.2:1"
+            ])),
+            addresses(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"])
+        );
+        assert_eq!(
+            interleave(addresses(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"])),
+            addresses(&["10.0.0.1:1", "[::1]:1", "10.0.0.2:1"])
+        );
+    }
+
+    /// Attempts that hang forever on port 1 and succeed after `port`
+    /// milliseconds otherwise.
+    async fn fake_attempt(address: SocketAddr) -> io::Result<(SocketAddr, Instant)> {
+        match address.port() {
+            1 => std::future::pending().await,
+            2 => Err(io::ErrorKind::ConnectionRefused.into()),
+            port => {
+                sleep(Duration::from_millis(port.into())).await;
+                Ok((address, Instant::now()))
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hanging_address_costs_one_delay() {
+        let start = Instant::now();
+        let (address, connected) = race(
+            addresses(&["[::1]:1", "10.0.0.1:10"]),
+            fake_attempt,
+            ATTEMPT_DELAY,
+            start + Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(address, "10.0.0.1:10".parse().unwrap());
+        assert_eq!(connected - start, ATTEMPT_DELAY + Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failure_starts_next_attempt_at_once() {
+        let start = Instant::now();
+        let (address, connected) = race(
+            addresses(&["[::1]:2", "10.0.0.1:10"]),
+            fake_attempt,
+            ATTEMPT_DELAY,
+            start + Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(address, "10.0.0.1:10".parse().unwrap());
+        assert_eq!(connected - start, Duration::from_millis(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn errors_and_timeouts() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_secs(5);
+        let error = race(
+            addresses(&["[::1]:2"]),
+            fake_attempt,
+            ATTEMPT_DELAY,
+            deadline,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        let error = race(
+            addresses(&["[::1]:1"]),
+            fake_attempt,
+            ATTEMPT_DELAY,
+            deadline,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(Instant::now(), deadline);
+    }
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stream = connect(&address, 24800, Instant::now() + Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
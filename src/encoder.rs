@@ -0,0 +1,174 @@
+use crate::parser::{
+    Clipboard, Command, Data, Flavour, Grab, HelloBack, Info, LegacyClipboard, Mark, Message,
+};
+
+/// Encodes a message sent by the client, without the frame length prefix.
+///
+/// Only messages that flow from client to server can be encoded, any other
+/// message is a bug in the caller.
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut v = Vec::new();
+    match message {
+        Message::HelloBack(hello_back) => encode_hello_back(&mut v, hello_back),
+        Message::Command(Command::KeepAlive) => v.extend_from_slice(b"CALV"),
+        Message::Command(Command::Grab(grab)) => encode_grab(&mut v, grab),
+        Message::Data(Data::Info(info)) => encode_info(&mut v, info),
+        Message::Data(Data::Clipboard(clipboard)) => encode_clipboard(&mut v, clipboard),
+        Message::Data(Data::LegacyClipboard(legacy)) => encode_legacy_clipboard(&mut v, legacy),
+        message => panic!("can not encode server message: {:?}", message),
+    }
+    v
+}
+
+fn encode_string(v: &mut Vec<u8>, s: &[u8]) {
+    v.extend_from_slice(&(s.len() as u32).to_be_bytes()[..]);
+    v.extend_from_slice(s);
+}
+
+// kMsgHelloBack = "Barrier%2i%2i%s";
+fn encode_hello_back(v: &mut Vec<u8>, hello_back: &HelloBack) {
+    match hello_back.flavour {
+        Flavour::Barrier => v.extend_from_slice(b"Barrier"),
+        Flavour::Synergy => v.extend_from_slice(b"Synergy"),
+    }
+    v.extend_from_slice(&hello_back.client_version.major.to_be_bytes()[..]);
+    v.extend_from_slice(&hello_back.client_version.minor.to_be_bytes()[..]);
+    encode_string(v, hello_back.name.as_bytes());
+}
+
+// kMsgDInfo = "DINF%2i%2i%2i%2i%2i%2i%2i";
+fn encode_info(v: &mut Vec<u8>, info: &Info) {
+    v.extend_from_slice(b"DINF");
+    v.extend_from_slice(&info.x.to_be_bytes()[..]);
+    v.extend_from_slice(&info.y.to_be_bytes()[..]);
+    v.extend_from_slice(&info.width.to_be_bytes()[..]);
+    v.extend_from_slice(&info.height.to_be_bytes()[..]);
+    v.extend_from_slice(&info.jump_zone.to_be_bytes()[..]);
+    v.extend_from_slice(&info.cursor_x.to_be_bytes()[..]);
+    v.extend_from_slice(&info.cursor_y.to_be_bytes()[..]);
+}
+
+// kMsgCClipboard = "CCLP%1i%4i";
+fn encode_grab(v: &mut Vec<u8>, grab: &Grab) {
+    v.extend_from_slice(b"CCLP");
+    v.push(grab.clipboard);
+    v.extend_from_slice(&grab.sequence_number.to_be_bytes()[..]);
+}
+
+// kMsgDClipboard = "DCLP%1i%4i%1i%s";
+fn encode_clipboard(v: &mut Vec<u8>, clipboard: &Clipboard) {
+    v.extend_from_slice(b"DCLP");
+    v.push(clipboard.clipboard);
+    v.extend_from_slice(&clipboard.sequence_number.to_be_bytes()[..]);
+    v.push(match clipboard.mark {
+        Mark::Start => 1,
+        Mark::Chunk => 2,
+        Mark::End => 3,
+    });
+    encode_string(v, &clipboard.data);
+}
+
+// kMsgDClipboard = "DCLP%1i%4i%s" before protocol 1.6.
+fn encode_legacy_clipboard(v: &mut Vec<u8>, legacy: &LegacyClipboard) {
+    v.extend_from_slice(b"DCLP");
+    v.push(legacy.clipboard);
+    v.extend_from_slice(&legacy.sequence_number.to_be_bytes()[..]);
+    encode_string(v, &legacy.data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ProtocolVersion;
+    use hex_literal::hex;
+
+    #[test]
+    fn hello_back() {
+        let message = Message::HelloBack(HelloBack {
+            flavour: Flavour::Barrier,
+            client_version: ProtocolVersion { major: 1, minor: 6 },
+            name: "desk".to_string(),
+        });
+        assert_eq!(
+            encode(&message),
+            hex!("42 61 72 72 69 65 72 00 01 00 06 00 00 00 04 64 65 73 6b").to_vec()
+        );
+    }
+
+    #[test]
+    fn hello_back_synergy() {
+        let message = Message::HelloBack(HelloBack {
+            flavour: Flavour::Synergy,
+            client_version: ProtocolVersion { major: 1, minor: 6 },
+            name: "desk".to_string(),
+        });
+        assert_eq!(
+            encode(&message),
+            hex!("53 79 6e 65 72 67 79 00 01 00 06 00 00 00 04 64 65 73 6b").to_vec()
+        );
+    }
+
+    #[test]
+    fn command_keep_alive() {
+        assert_eq!(
+            encode(&Message::Command(Command::KeepAlive)),
+            hex!("43 41 4c 56").to_vec()
+        );
+    }
+
+    #[test]
+    fn data_info() {
+        let message = Message::Data(Data::Info(Info {
+            x: -1280,
+            y: 0,
+            width: 3200,
+            height: 1080,
+            jump_zone: 1,
+            cursor_x: 315,
+            cursor_y: 664,
+        }));
+        assert_eq!(
+            encode(&message),
+            hex!("44 49 4e 46 fb 00 00 00 0c 80 04 38 00 01 01 3b 02 98").to_vec()
+        );
+    }
+
+    #[test]
+    fn command_grab() {
+        let message = Message::Command(Command::Grab(Grab {
+            clipboard: 1,
+            sequence_number: 42,
+        }));
+        assert_eq!(
+            encode(&message),
+            hex!("43 43 4c 50 01 00 00 00 2a").to_vec()
+        );
+    }
+
+    #[test]
+    fn data_clipboard() {
+        let message = Message::Data(Data::Clipboard(Clipboard {
+            clipboard: 0,
+            sequence_number: 7,
+            mark: Mark::Chunk,
+            data: b"abc".to_vec(),
+        }));
+        assert_eq!(
+            encode(&message),
+            hex!("44 43 4c 50 00 00 00 00 07 02 00 00 00 03 61 62 63").to_vec()
+        );
+    }
+
+    #[test]
+    fn data_legacy_clipboard() {
+        let message = Message::Data(Data::LegacyClipboard(LegacyClipboard {
+            clipboard: 0,
+            sequence_number: 7,
+            data: b"abc".to_vec(),
+        }));
+        assert_eq!(
+            encode(&message),
+            hex!("44 43 4c 50 00 00 00 00 07 00 00 00 03 61 62 63").to_vec()
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::transport::{self, Stream, Transport};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// The configured servers, most preferred first.
+#[derive(Debug)]
+pub struct Servers {
+    transports: Vec<Transport>,
+}
+
+impl Servers {
+    pub fn new(transports: Vec<Transport>) -> Self {
+        Self { transports }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transports.is_empty()
+    }
+
+    /// Connects to the most preferred reachable server, returning its index.
+    pub async fn connect(&self) -> Result<(usize, Box<dyn Stream>), transport::Error> {
+        self.connect_from(0).await
+    }
+
+    /// Tries every server once starting at `first` and wrapping around,
+    /// returning the error of the last one when none is reachable.
+    pub async fn connect_from(
+        &self,
+        first: usize,
+    ) -> Result<(usize, Box<dyn Stream>), transport::Error> {
+        let mut last_error = None;
+        for offset in 0..self.transports.len() {
+            let index = (first + offset) % self.transports.len();
+            match self.transports[index].connect().await {
+                Ok(stream) => return Ok((index, stream)),
+                Err(e) => {
+                    println!("Could not connect to server {}: {}", index + 1, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("no servers configured"))
+    }
+
+    /// Resolves once a server preferred over `current` is reachable again,
+    /// checking every `interval`.
+    pub async fn preferred(&self, current: usize, interval: Duration) -> (usize, Box<dyn Stream>) {
+        loop {
+            sleep(interval).await;
+            for (index, transport) in self.transports[..current].iter().enumerate() {
+                if let Ok(stream) = transport.connect().await {
+                    return (index, stream);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::net::UnixListener;
+
+    fn socket(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "barrier-failover-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn first_reachable_in_order() {
+        let (primary, backup, spare) = (socket("primary"), socket("backup"), socket("spare"));
+        let _backup = UnixListener::bind(&backup).unwrap();
+        let _spare = UnixListener::bind(&spare).unwrap();
+        let servers = Servers::new(vec![
+            Transport::Unix(primary.clone()),
+            Transport::Unix(backup.clone()),
+            Transport::Unix(spare.clone()),
+        ]);
+        assert_eq!(servers.connect().await.unwrap().0, 1);
+        // Failing over from the backup skips to the spare.
+        assert_eq!(servers.connect_from(2).await.unwrap().0, 2);
+        // And wraps around to the first reachable one.
+        drop(_spare);
+        std::fs::remove_file(&spare).unwrap();
+        assert_eq!(servers.connect_from(2).await.unwrap().0, 1);
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[tokio::test]
+    async fn none_reachable() {
+        let servers = Servers::new(vec![Transport::Unix(socket("gone"))]);
+        match servers.connect().await {
+            Err(transport::Error::ConnectUnix { .. }) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("connected to a missing socket"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_back_once_primary_returns() {
+        let (primary, backup) = (socket("returning"), socket("standby"));
+        let _backup = UnixListener::bind(&backup).unwrap();
+        let servers = Servers::new(vec![
+            Transport::Unix(primary.clone()),
+            Transport::Unix(backup.clone()),
+        ]);
+        let preferred = servers.preferred(1, Duration::from_millis(10));
+        let primary_returns = async {
+            sleep(Duration::from_millis(50)).await;
+            UnixListener::bind(&primary).unwrap()
+        };
+        let ((index, _), _listener) = tokio::join!(preferred, primary_returns);
+        assert_eq!(index, 0);
+        std::fs::remove_file(&primary).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+}
@@ -1,7 +1,17 @@
-use evdev_rs::enums::{int_to_ev_key, EventCode, EventType, EV_ABS, EV_KEY, EV_SYN};
+use crate::locks::{Lock, LockKey, Locks};
+use crate::parser::ServerOption;
+use evdev_rs::enums::{
+    int_to_ev_key, int_to_ev_led, int_to_event_type, EventCode, EventType, EV_ABS, EV_KEY, EV_LED,
+    EV_SYN,
+};
 use evdev_rs::{AbsInfo, Device, InputEvent, TimeVal, UInputDevice};
 use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd;
 use snafu::{OptionExt, ResultExt, Snafu};
+use std::mem;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::ptr;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -24,6 +34,8 @@ pub enum Error {
     },
     #[snafu(display("Could not map key for {}", device_type))]
     MapKey { device_type: DeviceType },
+    #[snafu(display("Could not read keyboard LEDs: {}", source))]
+    ReadLeds { source: nix::Error },
 }
 
 #[derive(Debug)]
@@ -45,6 +57,8 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub struct Mouse {
     device: UInputDevice,
+    /// Buttons pressed and not released yet.
+    pressed: Vec<MouseButton>,
 }
 
 impl Mouse {
@@ -97,7 +111,10 @@ impl Mouse {
         let device = UInputDevice::create_from_device(&evdevice).context(CreateUInputDevice {
             device_type: DeviceType::Mouse,
         })?;
-        Ok(Self { device })
+        Ok(Self {
+            device,
+            pressed: Vec::new(),
+        })
     }
 
     pub fn move_abs(&mut self, x: i32, y: i32) -> Result<()> {
@@ -133,34 +150,31 @@ impl Mouse {
 
     pub fn button_down(&mut self, button: impl Into<MouseButton>) -> Result<()> {
         let button = button.into();
-        self.device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(0, 0),
-                &EventCode::EV_KEY(button.into()),
-                1,
-            ))
-            .context(CreateEvent {
-                device_type: DeviceType::Mouse,
-            })?;
-        self.device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(0, 0),
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .context(CreateEvent {
-                device_type: DeviceType::Mouse,
-            })?;
+        self.write_button(button, 1)?;
+        if !self.pressed.contains(&button) {
+            self.pressed.push(button);
+        }
         Ok(())
     }
 
     pub fn button_up(&mut self, button: impl Into<MouseButton>) -> Result<()> {
         let button = button.into();
+        self.write_button(button, 0)?;
+        self.pressed.retain(|pressed| *pressed != button);
+        Ok(())
+    }
+
+    /// Releases every button still held, like when the cursor leaves us.
+    pub fn release_all(&mut self) -> Result<()> {
+        release_each(self.pressed.clone(), |button| self.button_up(button))
+    }
+
+    fn write_button(&mut self, button: MouseButton, value: i32) -> Result<()> {
         self.device
             .write_event(&InputEvent::new(
                 &TimeVal::new(0, 0),
                 &EventCode::EV_KEY(button.into()),
-                0,
+                value,
             ))
             .context(CreateEvent {
                 device_type: DeviceType::Mouse,
@@ -208,7 +222,7 @@ impl From<u8> for MouseButton {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MouseButton {
     Left,
     Right,
@@ -222,6 +236,12 @@ pub enum MouseButton {
 
 pub struct Keyboard {
     device: UInputDevice,
+    /// The uinput fd, still owned by `device`, which receives the LEDs the
+    /// compositor sets on us.
+    leds: RawFd,
+    locks: Locks,
+    /// Buttons of keys pressed and not released yet.
+    pressed: Vec<u16>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -253,6 +273,20 @@ pub enum Key {
 
 // looks like formula is button - 8
 
+/// Releases each of `held`, carrying on past failures, which stay held.
+/// Returns the first error and logs the rest.
+fn release_each<T>(held: Vec<T>, mut release: impl FnMut(T) -> Result<()>) -> Result<()> {
+    let mut result = Ok(());
+    for item in held {
+        match release(item) {
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(e) => println!("Failed to release: {}", e),
+            Ok(()) => {}
+        }
+    }
+    result
+}
+
 fn button_to_ev_key(button: u16) -> Result<EV_KEY> {
     let key = int_to_ev_key((button - 8).into()).context(MapKey {
         device_type: DeviceType::Keyboard,
@@ -273,43 +307,124 @@ impl Keyboard {
                     device_type: DeviceType::Keyboard,
                 })?;
         }
+        for led in &KEYBOARD_LEDS[..] {
+            evdevice
+                .enable(&EventCode::EV_LED(led.clone()))
+                .context(EnableDeviceProperty {
+                    device_type: DeviceType::Keyboard,
+                })?;
+        }
         let device = UInputDevice::create_from_device(&evdevice).context(CreateUInputDevice {
             device_type: DeviceType::Keyboard,
         })?;
-        Ok(Self { device })
+        // The returned file would close the fd on drop, libevdev owns it.
+        let leds = device
+            .fd()
+            .context(CreateDevice {
+                device_type: DeviceType::Keyboard,
+            })?
+            .into_raw_fd();
+        let flags = fcntl(leds, FcntlArg::F_GETFL).context(ReadLeds {})?;
+        let flags = OFlag::from_bits_truncate(flags) | OFlag::O_NONBLOCK;
+        fcntl(leds, FcntlArg::F_SETFL(flags)).context(ReadLeds {})?;
+        Ok(Self {
+            device,
+            leds,
+            locks: Locks::default(),
+            pressed: Vec::new(),
+        })
     }
 
+    /// Presses a key, lock keys follow the half-duplex options.
     pub fn key_down(&mut self, button: u16) -> Result<()> {
-        let button = button.into();
-        self.device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(0, 0),
-                &EventCode::EV_KEY(button_to_ev_key(button)?),
-                1,
-            ))
-            .context(CreateEvent {
-                device_type: DeviceType::Keyboard,
-            })?;
-        self.device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(0, 0),
-                &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
-                0,
-            ))
-            .context(CreateEvent {
-                device_type: DeviceType::Keyboard,
-            })?;
-        Ok(())
+        self.lock_key(button, true)
     }
 
     pub fn key_up(&mut self, button: u16) -> Result<()> {
-        let button = button.into();
+        self.lock_key(button, false)
+    }
+
+    /// Toggles our lock keys until they match the server's modifier mask.
+    pub fn sync_locks(&mut self, modifier_mask: u16) -> Result<()> {
+        self.read_leds()?;
+        for lock in self.locks.sync(modifier_mask) {
+            self.tap(lock.button())?;
+        }
+        Ok(())
+    }
+
+    /// Releases every key still held, like when the cursor leaves us. The
+    /// server sends the matching key ups to whichever screen it moved to.
+    pub fn release_all(&mut self) -> Result<()> {
+        release_each(self.pressed.clone(), |button| self.write_key(button, false))
+    }
+
+    pub fn set_options(&mut self, options: &[ServerOption]) {
+        self.locks.set_options(options);
+    }
+
+    pub fn reset_options(&mut self) {
+        self.locks.reset_options();
+    }
+
+    fn lock_key(&mut self, button: u16, down: bool) -> Result<()> {
+        let lock = match Lock::from_button(button) {
+            Some(lock) => lock,
+            None => return self.write_key(button, down),
+        };
+        self.read_leds()?;
+        match self.locks.key(lock, down) {
+            LockKey::Forward => self.write_key(button, down),
+            LockKey::Tap => self.tap(button),
+        }
+    }
+
+    fn tap(&mut self, button: u16) -> Result<()> {
+        self.write_key(button, true)?;
+        self.write_key(button, false)
+    }
+
+    fn write_key(&mut self, button: u16, down: bool) -> Result<()> {
+        let code = EventCode::EV_KEY(button_to_ev_key(button)?);
+        self.write(&code, down as i32)?;
+        if down {
+            if !self.pressed.contains(&button) {
+                self.pressed.push(button);
+            }
+        } else {
+            self.pressed.retain(|&pressed| pressed != button);
+        }
+        Ok(())
+    }
+
+    /// Takes in the lock LEDs the compositor set since we last looked.
+    fn read_leds(&mut self) -> Result<()> {
+        let mut buffer = [0u8; mem::size_of::<libc::input_event>()];
+        loop {
+            match unistd::read(self.leds, &mut buffer) {
+                Ok(read) if read == buffer.len() => {}
+                Ok(_) | Err(nix::Error::Sys(Errno::EAGAIN)) => return Ok(()),
+                Err(e) => return Err(e).context(ReadLeds {}),
+            }
+            // SAFETY: the kernel hands out whole input_events and any bytes
+            // are a valid one.
+            let event: libc::input_event = unsafe { ptr::read_unaligned(buffer.as_ptr().cast()) };
+            if int_to_event_type(event.type_.into()) != Some(EventType::EV_LED) {
+                continue;
+            }
+            let lock = match int_to_ev_led(event.code.into()) {
+                Some(EV_LED::LED_CAPSL) => Lock::Caps,
+                Some(EV_LED::LED_NUML) => Lock::Num,
+                Some(EV_LED::LED_SCROLLL) => Lock::Scroll,
+                _ => continue,
+            };
+            self.locks.led(lock, event.value != 0);
+        }
+    }
+
+    fn write(&mut self, code: &EventCode, value: i32) -> Result<()> {
         self.device
-            .write_event(&InputEvent::new(
-                &TimeVal::new(0, 0),
-                &EventCode::EV_KEY(button_to_ev_key(button)?),
-                0,
-            ))
+            .write_event(&InputEvent::new(&TimeVal::new(0, 0), code, value))
             .context(CreateEvent {
                 device_type: DeviceType::Keyboard,
             })?;
@@ -326,6 +441,8 @@ impl Keyboard {
     }
 }
 
+const KEYBOARD_LEDS: [EV_LED; 3] = [EV_LED::LED_NUML, EV_LED::LED_CAPSL, EV_LED::LED_SCROLLL];
+
 const KEYBOARD_KEYS: [EV_KEY; 146] = [
     EV_KEY::KEY_ESC,
     EV_KEY::KEY_1,
@@ -474,3 +591,25 @@ const KEYBOARD_KEYS: [EV_KEY; 146] = [
     EV_KEY::KEY_F24,
     EV_KEY::KEY_UNKNOWN,
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_carries_on_past_failures() {
+        let mut released = Vec::new();
+        let result = release_each(vec![1, 2, 3, 4], |button| {
+            if button % 2 == 0 {
+                return MapKey {
+                    device_type: DeviceType::Keyboard,
+                }
+                .fail();
+            }
+            released.push(button);
+            Ok(())
+        });
+        assert!(matches!(result, Err(Error::MapKey { .. })));
+        assert_eq!(released, [1, 3]);
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt, Snafu};
+use std::process::{Command, ExitStatus, Stdio};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not run {:?}: {}", command, source))]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("{:?} failed: {}", command, status))]
+    CommandFailed { command: String, status: ExitStatus },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Languages {
+    /// Two letter codes of the local keyboard layouts, in XKB group order.
+    #[serde(default)]
+    pub layouts: Vec<String>,
+    /// Shell command switching the XKB group, with `{group}` replaced by the
+    /// group index and `{language}` by the language code, for example
+    /// `swaymsg input type:keyboard xkb_switch_layout {group}`. Groups are
+    /// not switched without one.
+    pub group_command: Option<String>,
+}
+
+impl Languages {
+    /// Languages the server has that we have no layout for.
+    pub fn missing<'a>(&self, server: &'a [String]) -> Vec<&'a str> {
+        server
+            .iter()
+            .filter(|language| !self.layouts.contains(language))
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn group(&self, language: &str) -> Option<usize> {
+        self.layouts.iter().position(|layout| layout == language)
+    }
+}
+
+/// Switches the XKB group to follow the language of the server's key
+/// presses.
+#[derive(Debug)]
+pub struct Switcher<'a> {
+    languages: &'a Languages,
+    current: Option<usize>,
+}
+
+impl<'a> Switcher<'a> {
+    pub fn new(languages: &'a Languages) -> Self {
+        Self {
+            languages,
+            current: None,
+        }
+    }
+
+    pub fn languages(&self) -> &'a Languages {
+        self.languages
+    }
+
+    /// Builds the command switching to `language`, `None` when it is the
+    /// current group already or there is no way to switch to it.
+    fn command(&self, language: &str) -> Option<(usize, String)> {
+        let template = self.languages.group_command.as_ref()?;
+        let group = self.languages.group(language)?;
+        if self.current == Some(group) {
+            return None;
+        }
+        let command = template
+            .replace("{group}", &group.to_string())
+            .replace("{language}", language);
+        Some((group, command))
+    }
+
+    /// Makes `language` the active one before a key typed in it is pressed.
+    pub fn switch(&mut self, language: &str) -> Result<()> {
+        let (group, command) = match self.command(language) {
+            Some(switch) => switch,
+            None => return Ok(()),
+        };
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .status()
+            .context(Spawn {
+                command: command.clone(),
+            })?;
+        ensure!(status.success(), CommandFailed { command, status });
+        self.current = Some(group);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn languages(group_command: Option<&str>) -> Languages {
+        Languages {
+            layouts: vec!["en".to_string(), "de".to_string()],
+            group_command: group_command.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn missing_layouts() {
+        let server = ["en".to_string(), "ru".to_string(), "de".to_string()];
+        assert_eq!(languages(None).missing(&server), ["ru"]);
+    }
+
+    #[test]
+    fn group_command() {
+        let languages = languages(Some("switch {group} {language}"));
+        let switcher = Switcher::new(&languages);
+        assert_eq!(switcher.command("de"), Some((1, "switch 1 de".to_string())));
+        assert_eq!(switcher.command("ru"), None);
+    }
+
+    #[test]
+    fn switch_only_on_change() {
+        let languages = languages(Some("true"));
+        let mut switcher = Switcher::new(&languages);
+        switcher.switch("de").unwrap();
+        assert_eq!(switcher.current, Some(1));
+        assert_eq!(switcher.command("de"), None);
+        assert!(switcher.command("en").is_some());
+    }
+
+    #[test]
+    fn without_command() {
+        let languages = languages(None);
+        let mut switcher = Switcher::new(&languages);
+        assert!(switcher.switch("de").is_ok());
+        assert_eq!(switcher.current, None);
+    }
+
+    #[test]
+    fn failed_switch() {
+        let languages = languages(Some("exit 1"));
+        let mut switcher = Switcher::new(&languages);
+        match switcher.switch("de") {
+            Err(Error::CommandFailed { command, .. }) => assert_eq!(command, "exit 1"),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(switcher.current, None);
+    }
+}
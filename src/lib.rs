@@ -1,2 +1,19 @@
+pub mod backoff;
+pub mod client;
+pub mod clipboard;
+pub mod config;
+pub mod connect;
+pub mod encoder;
+pub mod failover;
 pub mod input;
+pub mod language;
+pub mod locks;
+pub mod mdns;
 pub mod parser;
+pub mod proxy;
+pub mod screen;
+pub mod screensaver;
+pub mod session;
+pub mod transfer;
+pub mod transport;
+pub mod writer;
@@ -0,0 +1,203 @@
+use crate::parser::ServerOption;
+
+/// Barrier's modifier mask bits for the lock keys.
+const CAPS_LOCK_MASK: u16 = 0x1000;
+const NUM_LOCK_MASK: u16 = 0x2000;
+const SCROLL_LOCK_MASK: u16 = 0x4000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lock {
+    Caps,
+    Num,
+    Scroll,
+}
+
+impl Lock {
+    pub const ALL: [Lock; 3] = [Lock::Caps, Lock::Num, Lock::Scroll];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn mask(self) -> u16 {
+        match self {
+            Lock::Caps => CAPS_LOCK_MASK,
+            Lock::Num => NUM_LOCK_MASK,
+            Lock::Scroll => SCROLL_LOCK_MASK,
+        }
+    }
+
+    /// The `DSOP` option making the key half-duplex.
+    fn half_duplex_option(self) -> &'static [u8; 4] {
+        match self {
+            Lock::Caps => b"HDCL",
+            Lock::Num => b"HDNL",
+            Lock::Scroll => b"HDSL",
+        }
+    }
+
+    /// Key button the server sends for the lock key.
+    pub fn button(self) -> u16 {
+        match self {
+            Lock::Caps => 66,
+            Lock::Num => 77,
+            Lock::Scroll => 78,
+        }
+    }
+
+    pub fn from_button(button: u16) -> Option<Lock> {
+        Lock::ALL
+            .iter()
+            .copied()
+            .find(|lock| lock.button() == button)
+    }
+}
+
+/// What pressing or releasing a lock key should do locally.
+#[derive(Debug, PartialEq)]
+pub enum LockKey {
+    /// Forward the press or release as is.
+    Forward,
+    /// Press and release the key, the server sends half-duplex keys as a
+    /// press when the lock turns on and a release when it turns off.
+    Tap,
+}
+
+/// Tracks the lock state of our keyboard so it can follow the server's.
+///
+/// Lock state belongs to the compositor and is shared by every keyboard, we
+/// learn it from the LEDs it sets on ours. The kernel only reports LEDs that
+/// change, so a lock stays unknown, and is left alone, until then.
+#[derive(Debug, Default)]
+pub struct Locks {
+    active: [bool; 3],
+    known: [bool; 3],
+    half_duplex: [bool; 3],
+}
+
+impl Locks {
+    pub fn is_active(&self, lock: Lock) -> bool {
+        self.active[lock.index()]
+    }
+
+    /// Records the state of a lock LED the compositor set.
+    pub fn led(&mut self, lock: Lock, on: bool) {
+        self.active[lock.index()] = on;
+        self.known[lock.index()] = true;
+    }
+
+    /// Locks to toggle for ours to match the server's modifier `mask`.
+    pub fn sync(&mut self, mask: u16) -> Vec<Lock> {
+        let toggled: Vec<Lock> = Lock::ALL
+            .iter()
+            .copied()
+            .filter(|&lock| self.known[lock.index()])
+            .filter(|&lock| self.is_active(lock) != (mask & lock.mask() != 0))
+            .collect();
+        for &lock in &toggled {
+            self.toggle(lock);
+        }
+        toggled
+    }
+
+    /// Records a lock key press or release we are about to send.
+    pub fn key(&mut self, lock: Lock, down: bool) -> LockKey {
+        if self.half_duplex[lock.index()] {
+            self.toggle(lock);
+            LockKey::Tap
+        } else {
+            if down {
+                self.toggle(lock);
+            }
+            LockKey::Forward
+        }
+    }
+
+    /// Applies the half-duplex options of a `DSOP` message.
+    pub fn set_options(&mut self, options: &[ServerOption]) {
+        for option in options {
+            if let Some(lock) = Lock::ALL
+                .iter()
+                .find(|lock| lock.half_duplex_option() == &option.id)
+            {
+                self.half_duplex[lock.index()] = option.value != 0;
+            }
+        }
+    }
+
+    pub fn reset_options(&mut self) {
+        self.half_duplex = [false; 3];
+    }
+
+    fn toggle(&mut self, lock: Lock) {
+        self.active[lock.index()] = !self.active[lock.index()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(id: &[u8; 4], value: u32) -> ServerOption {
+        ServerOption { id: *id, value }
+    }
+
+    fn known_off() -> Locks {
+        let mut locks = Locks::default();
+        for &lock in &Lock::ALL {
+            locks.led(lock, false);
+        }
+        locks
+    }
+
+    #[test]
+    fn sync_with_mask() {
+        let mut locks = known_off();
+        assert_eq!(locks.sync(0), []);
+        assert_eq!(locks.sync(0x1000 | 0x2000 | 0x2), [Lock::Caps, Lock::Num]);
+        assert!(locks.is_active(Lock::Caps));
+        assert_eq!(locks.sync(0x1000 | 0x2000), []);
+        assert_eq!(locks.sync(0x4000), [Lock::Caps, Lock::Num, Lock::Scroll]);
+    }
+
+    #[test]
+    fn unknown_locks_left_alone() {
+        let mut locks = Locks::default();
+        assert_eq!(locks.sync(0x1000 | 0x2000), []);
+        locks.led(Lock::Caps, true);
+        assert_eq!(locks.sync(0x2000), [Lock::Caps]);
+        assert!(!locks.is_active(Lock::Caps));
+        locks.led(Lock::Num, true);
+        assert_eq!(locks.sync(0x2000), []);
+    }
+
+    #[test]
+    fn lock_buttons() {
+        assert_eq!(Lock::from_button(66), Some(Lock::Caps));
+        assert_eq!(Lock::from_button(38), None);
+    }
+
+    #[test]
+    fn full_duplex_keys() {
+        let mut locks = known_off();
+        assert_eq!(locks.key(Lock::Caps, true), LockKey::Forward);
+        assert!(locks.is_active(Lock::Caps));
+        assert_eq!(locks.key(Lock::Caps, false), LockKey::Forward);
+        assert!(locks.is_active(Lock::Caps));
+        assert_eq!(locks.sync(0x1000), []);
+    }
+
+    #[test]
+    fn half_duplex_keys() {
+        let mut locks = Locks::default();
+        locks.set_options(&[option(b"HDCL", 1), option(b"HDSL", 0), option(b"XXXX", 1)]);
+        assert_eq!(locks.key(Lock::Caps, true), LockKey::Tap);
+        assert!(locks.is_active(Lock::Caps));
+        assert_eq!(locks.key(Lock::Caps, false), LockKey::Tap);
+        assert!(!locks.is_active(Lock::Caps));
+        assert_eq!(locks.key(Lock::Scroll, true), LockKey::Forward);
+
+        locks.reset_options();
+        assert_eq!(locks.key(Lock::Caps, true), LockKey::Forward);
+    }
+}
@@ -0,0 +1,595 @@
+use nom::bytes::complete::take;
+use nom::error::ErrorKind;
+use nom::multi::count;
+use nom::number::complete::{be_u16, be_u32, be_u8};
+use nom::IResult;
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Instant};
+
+/// Where mDNS queries go.
+pub const MDNS_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+/// Service type Barrier servers announce themselves with.
+pub const SERVICE: &str = "_barrier._tcp.local";
+/// How long to collect answers to a query.
+pub const QUERY_TIME: Duration = Duration::from_secs(1);
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Compression pointers followed before a name counts as a loop.
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    pub name: String,
+    pub kind: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Other(u16),
+}
+
+/// The parts of a DNS message we look at.
+#[derive(Debug, PartialEq)]
+pub struct Packet {
+    pub questions: Vec<Question>,
+    /// Answers followed by authority and additional records.
+    pub records: Vec<Record>,
+}
+
+/// A Barrier server found on the local network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// Name the server announces itself with.
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Service {
+    pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+        self.addresses
+            .iter()
+            .map(|&ip| SocketAddr::new(ip, self.port))
+            .collect()
+    }
+}
+
+/// Whether `host` is resolved over mDNS rather than DNS.
+pub fn is_local(host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    host.len() > ".local".len()
+        && host
+            .get(host.len() - ".local".len()..)
+            .is_some_and(|suffix| suffix.eq_ignore_ascii_case(".local"))
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// A query for each of `questions`, with id 0 as mDNS asks for.
+pub fn encode_query(questions: &[Question]) -> Vec<u8> {
+    let mut packet = vec![0; 4];
+    packet.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[0; 6]);
+    for question in questions {
+        encode_name(&mut packet, &question.name);
+        packet.extend_from_slice(&question.kind.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    packet
+}
+
+pub fn encode_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+pub fn parse(packet: &[u8]) -> IResult<&[u8], Packet> {
+    let (input, _id) = be_u16(packet)?;
+    let (input, _flags) = be_u16(input)?;
+    let (input, questions) = be_u16(input)?;
+    let (input, answers) = be_u16(input)?;
+    let (input, authorities) = be_u16(input)?;
+    let (input, additionals) = be_u16(input)?;
+    let (input, questions) = count(|i| question(packet, i), questions as usize)(input)?;
+    let records = answers as usize + authorities as usize + additionals as usize;
+    let (input, records) = count(|i| record(packet, i), records)(input)?;
+    Ok((input, Packet { questions, records }))
+}
+
+fn question<'a>(packet: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], Question> {
+    let (input, name) = name(packet, input)?;
+    let (input, kind) = be_u16(input)?;
+    let (input, _class) = be_u16(input)?;
+    Ok((input, Question { name, kind }))
+}
+
+fn record<'a>(packet: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], Record> {
+    let (input, owner) = name(packet, input)?;
+    let (input, kind) = be_u16(input)?;
+    let (input, _class) = be_u16(input)?;
+    let (input, _ttl) = be_u32(input)?;
+    let (input, length) = be_u16(input)?;
+    let (input, rdata) = take(length)(input)?;
+    let data = match kind {
+        TYPE_A if rdata.len() == 4 => {
+            RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+        }
+        TYPE_AAAA if rdata.len() == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(rdata);
+            RecordData::Aaaa(Ipv6Addr::from(octets))
+        }
+        TYPE_PTR => RecordData::Ptr(name(packet, rdata)?.1),
+        TYPE_SRV => {
+            let (rdata, _priority) = be_u16(rdata)?;
+            let (rdata, _weight) = be_u16(rdata)?;
+            let (rdata, port) = be_u16(rdata)?;
+            let (_, target) = name(packet, rdata)?;
+            RecordData::Srv { port, target }
+        }
+        other => RecordData::Other(other),
+    };
+    Ok((input, Record { name: owner, data }))
+}
+
+/// A possibly compressed name, pointers are offsets into `packet`.
+fn name<'a>(packet: &'a [u8], input: &'a [u8]) -> IResult<&'a [u8], String> {
+    let mut labels = Vec::new();
+    let mut rest = input;
+    let mut after = None;
+    let mut pointers = 0;
+    loop {
+        let (next, length) = be_u8(rest)?;
+        match length {
+            0 => {
+                let rest = after.unwrap_or(next);
+                return Ok((rest, labels.join(".")));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let (next, low) = be_u8(next)?;
+                let offset = ((length as usize & 0x3f) << 8) | low as usize;
+                pointers += 1;
+                if pointers > MAX_POINTERS || offset >= packet.len() {
+                    return Err(nom::Err::Error((input, ErrorKind::Verify)));
+                }
+                after.get_or_insert(next);
+                rest = &packet[offset..];
+            }
+            length => {
+                let (next, label) = take(length)(next)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                rest = next;
+            }
+        }
+    }
+}
+
+/// Asks the local network over multicast DNS.
+#[derive(Debug, Clone)]
+pub struct Mdns {
+    group: SocketAddrV4,
+    /// Interface to send queries from, any when unspecified.
+    interface: Ipv4Addr,
+    wait: Duration,
+}
+
+impl Default for Mdns {
+    fn default() -> Self {
+        Self::new(MDNS_GROUP, Ipv4Addr::UNSPECIFIED, QUERY_TIME)
+    }
+}
+
+impl Mdns {
+    pub fn new(group: SocketAddrV4, interface: Ipv4Addr, wait: Duration) -> Self {
+        Self {
+            group,
+            interface,
+            wait,
+        }
+    }
+
+    /// Sends `questions` from a port of our own, so responders answer us
+    /// directly, and collects records until `done` or the wait is over.
+    async fn query<F>(&self, questions: &[Question], done: F) -> io::Result<Vec<Record>>
+    where
+        F: Fn(&[Record]) -> bool,
+    {
+        let socket = UdpSocket::bind((self.interface, 0)).await?;
+        if !self.interface.is_unspecified() {
+            SockRef::from(&socket).set_multicast_if_v4(&self.interface)?;
+        }
+        socket.set_multicast_loop_v4(true)?;
+        socket.send_to(&encode_query(questions), self.group).await?;
+
+        let deadline = Instant::now() + self.wait;
+        let mut records = Vec::new();
+        let mut buffer = [0; 9000];
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+            let (length, _) = received?;
+            // Whatever else is on the network is none of our business.
+            if let Ok((_, packet)) = parse(&buffer[..length]) {
+                records.extend(packet.records);
+                if done(&records) {
+                    break;
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Finds every Barrier server announcing itself, asking for whatever
+    /// their answers leave out.
+    pub async fn browse(&self) -> io::Result<Vec<Service>> {
+        let mut questions = vec![Question {
+            name: SERVICE.to_string(),
+            kind: TYPE_PTR,
+        }];
+        let mut records = Vec::new();
+        // The instances, then their SRV records, then the targets' addresses.
+        for _ in 0..3 {
+            records.extend(self.query(&questions, |_| false).await?);
+            questions = missing(&records);
+            if questions.is_empty() {
+                break;
+            }
+        }
+        Ok(services(&records))
+    }
+
+    /// Addresses of a `.local` host.
+    pub async fn lookup_host(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let questions = [TYPE_A, TYPE_AAAA].map(|kind| Question {
+            name: host.to_string(),
+            kind,
+        });
+        let records = self
+            .query(&questions, |records| !addresses(records, host).is_empty())
+            .await?;
+        Ok(addresses(&records, host))
+    }
+}
+
+fn addresses(records: &[Record], host: &str) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    for record in records
+        .iter()
+        .filter(|record| same_name(&record.name, host))
+    {
+        let address = match record.data {
+            RecordData::A(ip) => IpAddr::V4(ip),
+            RecordData::Aaaa(ip) => IpAddr::V6(ip),
+            _ => continue,
+        };
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+fn instances(records: &[Record]) -> Vec<&str> {
+    let mut instances = Vec::new();
+    for record in records
+        .iter()
+        .filter(|record| same_name(&record.name, SERVICE))
+    {
+        if let RecordData::Ptr(instance) = &record.data {
+            if !instances.contains(&instance.as_str()) {
+                instances.push(instance.as_str());
+            }
+        }
+    }
+    instances
+}
+
+fn srv<'a>(records: &'a [Record], instance: &str) -> Option<(u16, &'a str)> {
+    records.iter().find_map(|record| match &record.data {
+        RecordData::Srv { port, target } if same_name(&record.name, instance) => {
+            Some((*port, target.as_str()))
+        }
+        _ => None,
+    })
+}
+
+/// Questions for the SRV records and addresses we have no answer for yet.
+fn missing(records: &[Record]) -> Vec<Question> {
+    let mut questions = Vec::new();
+    for instance in instances(records) {
+        match srv(records, instance) {
+            None => questions.push(Question {
+                name: instance.to_string(),
+                kind: TYPE_SRV,
+            }),
+            Some((_, target)) if addresses(records, target).is_empty() => {
+                for kind in [TYPE_A, TYPE_AAAA] {
+                    questions.push(Question {
+                        name: target.to_string(),
+                        kind,
+                    });
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    questions
+}
+
+fn services(records: &[Record]) -> Vec<Service> {
+    instances(records)
+        .into_iter()
+        .filter_map(|instance| {
+            let (port, target) = srv(records, instance)?;
+            let addresses = addresses(records, target);
+            if addresses.is_empty() {
+                return None;
+            }
+            let name = instance
+                .trim_end_matches('.')
+                .strip_suffix(SERVICE)
+                .and_then(|name| name.strip_suffix('.'))
+                .unwrap_or(instance);
+            Some(Service {
+                name: name.to_string(),
+                host: target.to_string(),
+                port,
+                addresses,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect::resolve_with;
+    use hex_literal::hex;
+
+    /// Set in the class of records that replace rather than add to a cache.
+    const CACHE_FLUSH: u16 = 0x8000;
+
+    /// An answer with the names uncompressed.
+    fn response(records: &[Record]) -> Vec<u8> {
+        let mut packet = hex!("00 00 84 00 00 00").to_vec();
+        packet.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        for record in records {
+            encode_name(&mut packet, &record.name);
+            let mut rdata = Vec::new();
+            let kind = match &record.data {
+                RecordData::A(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    TYPE_A
+                }
+                RecordData::Aaaa(ip) => {
+                    rdata.extend_from_slice(&ip.octets());
+                    TYPE_AAAA
+                }
+                RecordData::Ptr(name) => {
+                    encode_name(&mut rdata, name);
+                    TYPE_PTR
+                }
+                RecordData::Srv { port, target } => {
+                    rdata.extend_from_slice(&[0, 0, 0, 0]);
+                    rdata.extend_from_slice(&port.to_be_bytes());
+                    encode_name(&mut rdata, target);
+                    TYPE_SRV
+                }
+                RecordData::Other(kind) => *kind,
+            };
+            packet.extend_from_slice(&kind.to_be_bytes());
+            packet.extend_from_slice(&(CLASS_IN | CACHE_FLUSH).to_be_bytes());
+            packet.extend_from_slice(&120u32.to_be_bytes());
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&rdata);
+        }
+        packet
+    }
+
+    fn record(name: &str, data: RecordData) -> Record {
+        Record {
+            name: name.to_string(),
+            data,
+        }
+    }
+
+    fn desk() -> Vec<Record> {
+        vec![
+            record(SERVICE, RecordData::Ptr(format!("Desk.{}", SERVICE))),
+            record(
+                &format!("Desk.{}", SERVICE),
+                RecordData::Srv {
+                    port: 24810,
+                    target: "desk.local".to_string(),
+                },
+            ),
+            record("desk.local", RecordData::A(Ipv4Addr::LOCALHOST)),
+        ]
+    }
+
+    #[test]
+    fn query() {
+        let query = encode_query(&[Question {
+            name: SERVICE.to_string(),
+            kind: TYPE_PTR,
+        }]);
+        assert_eq!(
+            query,
+            hex!(
+                "00 00 00 00 00 01 00 00 00 00 00 00
+                 08 5f 62 61 72 72 69 65 72 04 5f 74 63 70 05 6c 6f 63 61 6c 00
+                 00 0c 00 01"
+            )
+        );
+        let (_, packet) = parse(&query).unwrap();
+        assert_eq!(packet.questions[0].name, SERVICE);
+        assert!(packet.records.is_empty());
+    }
+
+    #[test]
+    fn compressed_names() {
+        let packet = hex!(
+            "00 00 84 00 00 00 00 02 00 00 00 00
+             08 5f 62 61 72 72 69 65 72 04 5f 74 63 70 05 6c 6f 63 61 6c 00
+             00 0c 00 01 00 00 00 78 00 07 04 44 65 73 6b c0 0c
+             c0 2b 00 21 80 01 00 00 00 78 00 0d 00 00 00 00 60 e0 04 64 65 73 6b c0 1a"
+        );
+        let (_, packet) = parse(&packet).unwrap();
+        assert_eq!(
+            packet.records,
+            [
+                record(SERVICE, RecordData::Ptr(format!("Desk.{}", SERVICE))),
+                record(
+                    &format!("Desk.{}", SERVICE),
+                    RecordData::Srv {
+                        port: 24800,
+                        target: "desk.local".to_string(),
+                    },
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pointer_loop() {
+        let packet = hex!("00 00 84 00 00 00 00 01 00 00 00 00 c0 0c");
+        assert!(parse(&packet).is_err());
+    }
+
+    #[test]
+    fn services_from_records() {
+        let records = desk();
+        assert!(missing(&records).is_empty());
+        assert_eq!(
+            services(&records),
+            [Service {
+                name: "Desk".to_string(),
+                host: "desk.local".to_string(),
+                port: 24810,
+                addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            }]
+        );
+        assert_eq!(
+            missing(&records[..2]),
+            [
+                Question {
+                    name: "desk.local".to_string(),
+                    kind: TYPE_A
+                },
+                Question {
+                    name: "desk.local".to_string(),
+                    kind: TYPE_AAAA
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn local_names() {
+        assert!(is_local("desk.local"));
+        assert!(is_local("Desk.LOCAL."));
+        assert!(!is_local(".local"));
+        assert!(!is_local("desk.localdomain"));
+    }
+
+    /// A responder on loopback answering each question with the matching
+    /// records of `desk`, one packet per record.
+    async fn responder() -> Mdns {
+        let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        socket
+            .join_multicast_v4(MDNS_GROUP.ip(), &Ipv4Addr::LOCALHOST)
+            .unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let socket = UdpSocket::from_std(socket).unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            while let Ok((length, from)) = socket.recv_from(&mut buffer).await {
+                let (_, query) = parse(&buffer[..length]).unwrap();
+                for question in query.questions {
+                    for answer in desk().into_iter().filter(|record| {
+                        same_name(&record.name, &question.name)
+                            && match record.data {
+                                RecordData::A(_) => question.kind == TYPE_A,
+                                RecordData::Ptr(_) => question.kind == TYPE_PTR,
+                                RecordData::Srv { .. } => question.kind == TYPE_SRV,
+                                _ => false,
+                            }
+                    }) {
+                        socket.send_to(&response(&[answer]), from).await.unwrap();
+                    }
+                }
+            }
+        });
+        Mdns::new(
+            SocketAddrV4::new(*MDNS_GROUP.ip(), port),
+            Ipv4Addr::LOCALHOST,
+            Duration::from_millis(200),
+        )
+    }
+
+    #[tokio::test]
+    async fn browse_asks_for_what_is_missing() {
+        let mdns = responder().await;
+        let services = mdns.browse().await.unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].name, "Desk");
+        assert_eq!(
+            services[0].socket_addrs(),
+            ["127.0.0.1:24810".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_host() {
+        let mdns = responder().await;
+        assert_eq!(
+            mdns.lookup_host("Desk.local").await.unwrap(),
+            [IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        );
+        assert!(mdns.lookup_host("laptop.local").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_addresses() {
+        let mdns = responder().await;
+        let unknown = |_, _| async { Err(io::Error::from(io::ErrorKind::NotFound)) };
+        let resolve = |address| resolve_with(&mdns, unknown, address, 24800);
+        let auto = ["127.0.0.1:24810".parse().unwrap()];
+        assert_eq!(resolve("auto").await.unwrap(), auto);
+        assert_eq!(resolve("desk").await.unwrap(), auto);
+        assert_eq!(
+            resolve("desk.local:24801").await.unwrap(),
+            ["127.0.0.1:24801".parse().unwrap()]
+        );
+        assert_eq!(
+            resolve("laptop.local").await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,8 +1,10 @@
+use crate::session::{Context, Feature};
 use nom::branch::alt;
 use nom::bytes::complete::tag;
+use nom::combinator::map;
 use nom::error::ErrorKind;
-use nom::multi::length_data;
-use nom::number::complete::{be_u16, be_u32, be_u8};
+use nom::multi::{count, length_data};
+use nom::number::complete::{be_i16, be_u16, be_u32, be_u8};
 use nom::Err;
 use std::fmt;
 
@@ -10,6 +12,7 @@ use std::fmt;
 pub enum ParseError<I> {
     Other(I, ErrorKind),
     NotImplemented(I),
+    NotSupported(I, Feature),
 }
 
 impl fmt::Debug for ParseError<&[u8]> {
@@ -24,6 +27,11 @@ impl fmt::Debug for ParseError<&[u8]> {
                 &input[4..],
                 input
             ),
+            ParseError::NotSupported(input, feature) => write!(
+                f,
+                "Request not supported by negotiated protocol version: {:?}, input: {:x?}",
+                feature, input
+            ),
             ParseError::Other(input, kind) => write!(f, "Parse error kind {:?} {:x?}", input, kind),
         }
     }
@@ -41,26 +49,62 @@ impl<I> nom::error::ParseError<I> for ParseError<I> {
 
 pub type IResult<I, O, E = ParseError<I>> = Result<(I, O), Err<E>>;
 
-pub fn parse_frame(input: &[u8]) -> IResult<&[u8], Message> {
-    message(input)
+/// Parses a frame, rejecting messages the negotiated protocol version does
+/// not have.
+pub fn parse_frame<'a>(input: &'a [u8], context: &Context) -> IResult<&'a [u8], Message> {
+    let (rest, message) = if context.supports(Feature::ClipboardChunks) {
+        message(input)?
+    } else {
+        alt((legacy_clipboard, message))(input)?
+    };
+    match required_feature(&message) {
+        Some(feature) if !context.supports(feature) => {
+            Err(nom::Err::Failure(ParseError::NotSupported(input, feature)))
+        }
+        _ => Ok((rest, message)),
+    }
+}
+
+fn required_feature(message: &Message) -> Option<Feature> {
+    match message {
+        Message::Data(Data::Clipboard(_)) => Some(Feature::ClipboardChunks),
+        Message::Data(Data::SecureInput(_)) => Some(Feature::SecureInputNotification),
+        Message::Data(Data::FileTransfer(_)) | Message::Data(Data::DragInfo(_)) => {
+            Some(Feature::FileTransfer)
+        }
+        Message::Data(Data::Languages(_))
+        | Message::Data(Data::KeyDown(Key {
+            language: Some(_), ..
+        })) => Some(Feature::LanguageSync),
+        _ => None,
+    }
 }
 
 pub fn message(input: &[u8]) -> IResult<&[u8], Message> {
+    // alt takes at most 21 parsers, so they are grouped.
     alt((
-        mouse_move,
-        mouse_down,
-        mouse_up,
-        key_down,
-        key_up,
-        hello,
-        keep_alive,
-        query_info,
-        info_ack,
-        reset_options,
-        options,
-        enter,
-        leave,
-        clipboard,
+        alt((
+            mouse_move,
+            mouse_down,
+            mouse_up,
+            key_down,
+            key_down_language,
+            key_up,
+        )),
+        alt((
+            hello,
+            keep_alive,
+            query_info,
+            info_ack,
+            reset_options,
+            options,
+            enter,
+            leave,
+            grab,
+            screensaver,
+        )),
+        alt((clipboard, secure_input, file_transfer, drag_info, languages)),
+        alt((incompatible, busy, unknown, bad)),
         not_implemented,
     ))(input)
 }
@@ -80,6 +124,7 @@ pub fn key_down(input: &[u8]) -> IResult<&[u8], Message> {
             id,
             modifier_mask,
             button,
+            language: None,
         })),
     ))
 }
@@ -95,14 +140,44 @@ pub fn key_up(input: &[u8]) -> IResult<&[u8], Message> {
             id,
             modifier_mask,
             button,
+            language: None,
+        })),
+    ))
+}
+
+/// Key press carrying the language the server typed it in.
+pub fn key_down_language(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("DKDL")(input)?;
+    let (input, id) = be_u16(input)?;
+    let (input, modifier_mask) = be_u16(input)?;
+    let (input, button) = be_u16(input)?;
+    let (input, language) = length_data(be_u32)(input)?;
+    Ok((
+        input,
+        Message::Data(Data::KeyDown(Key {
+            id,
+            modifier_mask,
+            button,
+            language: Some(String::from_utf8_lossy(language).into_owned()),
         })),
     ))
 }
 
+/// The server's keyboard languages as concatenated two letter codes.
+pub fn languages(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("LSYN")(input)?;
+    let (input, codes) = length_data(be_u32)(input)?;
+    let languages = codes
+        .chunks(2)
+        .map(|code| String::from_utf8_lossy(code).into_owned())
+        .collect();
+    Ok((input, Message::Data(Data::Languages(languages))))
+}
+
 pub fn mouse_move(input: &[u8]) -> IResult<&[u8], Message> {
     let (input, _) = tag("DMMV")(input)?;
-    let (input, x) = be_u16(input)?;
-    let (input, y) = be_u16(input)?;
+    let (input, x) = be_i16(input)?;
+    let (input, y) = be_i16(input)?;
     Ok((input, Message::Data(Data::MouseMove(MouseMove { x, y }))))
 }
 
@@ -118,13 +193,21 @@ pub fn mouse_up(input: &[u8]) -> IResult<&[u8], Message> {
     Ok((input, Message::Data(Data::MouseUp(Mouse { id }))))
 }
 
+pub fn flavour(input: &[u8]) -> IResult<&[u8], Flavour> {
+    alt((
+        map(tag("Barrier"), |_| Flavour::Barrier),
+        map(tag("Synergy"), |_| Flavour::Synergy),
+    ))(input)
+}
+
 pub fn hello(input: &[u8]) -> IResult<&[u8], Message> {
-    let (input, _) = tag("Barrier")(input)?;
+    let (input, flavour) = flavour(input)?;
     let (input, major) = be_u16(input)?;
     let (input, minor) = be_u16(input)?;
     Ok((
         input,
         Message::Hello(Hello {
+            flavour,
             server_version: ProtocolVersion { major, minor },
         }),
     ))
@@ -152,14 +235,22 @@ pub fn reset_options(input: &[u8]) -> IResult<&[u8], Message> {
 
 pub fn options(input: &[u8]) -> IResult<&[u8], Message> {
     let (input, _) = tag("DSOP")(input)?;
-    let (input, _) = length_data(be_u32)(input)?;
-    Ok((input, Message::Data(Data::Options(Options {}))))
+    let (input, length) = be_u32(input)?;
+    let (input, values) = count(be_u32, length as usize)(input)?;
+    let options = values
+        .chunks_exact(2)
+        .map(|pair| ServerOption {
+            id: pair[0].to_be_bytes(),
+            value: pair[1],
+        })
+        .collect();
+    Ok((input, Message::Data(Data::Options(Options { options }))))
 }
 
 pub fn enter(input: &[u8]) -> IResult<&[u8], Message> {
     let (input, _) = tag("CINN")(input)?;
-    let (input, x) = be_u16(input)?;
-    let (input, y) = be_u16(input)?;
+    let (input, x) = be_i16(input)?;
+    let (input, y) = be_i16(input)?;
     let (input, sequence_number) = be_u32(input)?;
     let (input, key_modifier_mask) = be_u16(input)?;
     Ok((
@@ -178,25 +269,137 @@ pub fn leave(input: &[u8]) -> IResult<&[u8], Message> {
     Ok((input, Message::Command(Command::Leave)))
 }
 
+pub fn grab(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("CCLP")(input)?;
+    let (input, clipboard) = be_u8(input)?;
+    let (input, sequence_number) = be_u32(input)?;
+    Ok((
+        input,
+        Message::Command(Command::Grab(Grab {
+            clipboard,
+            sequence_number,
+        })),
+    ))
+}
+
+pub fn screensaver(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("CSEC")(input)?;
+    let (input, active) = be_u8(input)?;
+    Ok((input, Message::Command(Command::Screensaver(active != 0))))
+}
+
+pub fn mark(input: &[u8]) -> IResult<&[u8], Mark> {
+    let (rest, mark) = be_u8(input)?;
+    match mark {
+        1 => Ok((rest, Mark::Start)),
+        2 => Ok((rest, Mark::Chunk)),
+        3 => Ok((rest, Mark::End)),
+        _ => Err(nom::Err::Error(ParseError::Other(input, ErrorKind::Verify))),
+    }
+}
+
 pub fn clipboard(input: &[u8]) -> IResult<&[u8], Message> {
     let (input, _) = tag("DCLP")(input)?;
     let (input, clipboard) = be_u8(input)?;
     let (input, sequence_number) = be_u32(input)?;
-    let (input, mark) = be_u8(input)?;
-    let (input, _) = length_data(be_u32)(input)?;
+    let (input, mark) = mark(input)?;
+    let (input, data) = length_data(be_u32)(input)?;
     Ok((
         input,
         Message::Data(Data::Clipboard(Clipboard {
             clipboard,
             sequence_number,
             mark,
+            data: data.to_vec(),
+        })),
+    ))
+}
+
+/// Clipboard data before protocol 1.6, always sent in a single message.
+pub fn legacy_clipboard(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("DCLP")(input)?;
+    let (input, clipboard) = be_u8(input)?;
+    let (input, sequence_number) = be_u32(input)?;
+    let (input, data) = length_data(be_u32)(input)?;
+    Ok((
+        input,
+        Message::Data(Data::LegacyClipboard(LegacyClipboard {
+            clipboard,
+            sequence_number,
+            data: data.to_vec(),
+        })),
+    ))
+}
+
+pub fn secure_input(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("SECN")(input)?;
+    let (input, app) = length_data(be_u32)(input)?;
+    Ok((
+        input,
+        Message::Data(Data::SecureInput(SecureInput {
+            app: String::from_utf8_lossy(app).into_owned(),
+        })),
+    ))
+}
+
+pub fn file_transfer(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("DFTR")(input)?;
+    let (input, mark) = mark(input)?;
+    let (input, data) = length_data(be_u32)(input)?;
+    Ok((
+        input,
+        Message::Data(Data::FileTransfer(FileTransfer {
+            mark,
+            data: data.to_vec(),
         })),
     ))
 }
 
+/// The dragged files' paths on the source screen, each followed by a comma.
+pub fn drag_info(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("DDRG")(input)?;
+    let (input, count) = be_u16(input)?;
+    let (input, paths) = length_data(be_u32)(input)?;
+    let files = String::from_utf8_lossy(paths)
+        .split(',')
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok((
+        input,
+        Message::Data(Data::DragInfo(DragInfo { count, files })),
+    ))
+}
+
+pub fn incompatible(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("EICV")(input)?;
+    let (input, major) = be_u16(input)?;
+    let (input, minor) = be_u16(input)?;
+    Ok((
+        input,
+        Message::Error(Error::Incompatible(ProtocolVersion { major, minor })),
+    ))
+}
+
+pub fn busy(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("EBSY")(input)?;
+    Ok((input, Message::Error(Error::Busy)))
+}
+
+pub fn unknown(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("EUNK")(input)?;
+    Ok((input, Message::Error(Error::Unkown)))
+}
+
+pub fn bad(input: &[u8]) -> IResult<&[u8], Message> {
+    let (input, _) = tag("EBAD")(input)?;
+    Ok((input, Message::Error(Error::Bad)))
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Message {
     Hello(Hello),
+    HelloBack(HelloBack),
     Query(Query),
     Command(Command),
     Data(Data),
@@ -215,10 +418,14 @@ pub enum Command {
     ResetOptions,
     Enter(Enter),
     Leave,
+    Grab(Grab),
+    /// Whether the server's screensaver started or stopped.
+    Screensaver(bool),
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Data {
+    Info(Info),
     MouseMove(MouseMove),
     MouseDown(Mouse),
     MouseUp(Mouse),
@@ -226,10 +433,17 @@ pub enum Data {
     KeyUp(Key),
     Options(Options),
     Clipboard(Clipboard),
+    LegacyClipboard(LegacyClipboard),
+    SecureInput(SecureInput),
+    FileTransfer(FileTransfer),
+    DragInfo(DragInfo),
+    /// Two letter codes of the server's keyboard languages.
+    Languages(Vec<String>),
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
+    Incompatible(ProtocolVersion),
     Busy,
     Unkown,
     Bad,
@@ -237,16 +451,23 @@ pub enum Error {
 
 #[derive(Debug, PartialEq)]
 pub struct Enter {
-    pub x: u16,
-    pub y: u16,
+    pub x: i16,
+    pub y: i16,
     pub sequence_number: u32,
     pub key_modifier_mask: u16,
 }
 
+/// Takes ownership of a clipboard, sent by whichever side copied last.
+#[derive(Debug, PartialEq)]
+pub struct Grab {
+    pub clipboard: u8,
+    pub sequence_number: u32,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct MouseMove {
-    pub x: u16,
-    pub y: u16,
+    pub x: i16,
+    pub y: i16,
 }
 
 #[derive(Debug, PartialEq)]
@@ -259,27 +480,133 @@ pub struct Key {
     pub id: u16,
     pub modifier_mask: u16,
     pub button: u16,
+    /// Language the key was typed in, only sent with key presses.
+    pub language: Option<String>,
+}
+
+/// The hello tag identifies which family of servers we are talking to.
+/// Synergy 1.x and Deskflow send `Synergy` but otherwise use the same framing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flavour {
+    Barrier,
+    Synergy,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Hello {
+    pub flavour: Flavour,
     pub server_version: ProtocolVersion,
 }
 
 #[derive(Debug, PartialEq)]
+pub struct HelloBack {
+    pub flavour: Flavour,
+    pub client_version: ProtocolVersion,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Info {
+    pub x: i16,
+    pub y: i16,
+    pub width: i16,
+    pub height: i16,
+    pub jump_zone: i16,
+    pub cursor_x: i16,
+    pub cursor_y: i16,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SecureInput {
+    pub app: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct ProtocolVersion {
     pub major: u16,
     pub minor: u16,
 }
 
+/// Options the server sets for this screen, as id and value pairs.
 #[derive(Debug, PartialEq)]
-pub struct Options {}
+pub struct Options {
+    pub options: Vec<ServerOption>,
+}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerOption {
+    /// Four letter option name, like `HDCL`.
+    pub id: [u8; 4],
+    pub value: u32,
+}
+
+/// Position of a chunk within a streamed transfer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mark {
+    /// Carries the total size of the transfer as a decimal string.
+    Start,
+    Chunk,
+    End,
+}
+
+#[derive(PartialEq)]
 pub struct Clipboard {
-    clipboard: u8,
-    sequence_number: u32,
-    mark: u8,
+    pub clipboard: u8,
+    pub sequence_number: u32,
+    pub mark: Mark,
+    pub data: Vec<u8>,
+}
+
+#[derive(PartialEq)]
+pub struct FileTransfer {
+    pub mark: Mark,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DragInfo {
+    /// Number of files the server announced.
+    pub count: u16,
+    /// Paths of the dragged files on the server.
+    pub files: Vec<String>,
+}
+
+#[derive(PartialEq)]
+pub struct LegacyClipboard {
+    pub clipboard: u8,
+    pub sequence_number: u32,
+    pub data: Vec<u8>,
+}
+
+// Clipboard data can be large, so only its length is shown.
+impl fmt::Debug for Clipboard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Clipboard")
+            .field("clipboard", &self.clipboard)
+            .field("sequence_number", &self.sequence_number)
+            .field("mark", &self.mark)
+            .field("data_len", &self.data.len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for FileTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FileTransfer")
+            .field("mark", &self.mark)
+            .field("data_len", &self.data.len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for LegacyClipboard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LegacyClipboard")
+            .field("clipboard", &self.clipboard)
+            .field("sequence_number", &self.sequence_number)
+            .field("data_len", &self.data.len())
+            .finish()
+    }
 }
 
 #[cfg(test)]
@@ -287,6 +614,14 @@ mod tests {
     use super::*;
     use hex_literal::hex;
 
+    fn context(major: u16, minor: u16) -> Context {
+        Context::negotiate(&Hello {
+            flavour: Flavour::Barrier,
+            server_version: ProtocolVersion { major, minor },
+        })
+        .unwrap()
+    }
+
     #[test]
     fn hello() {
         // kMsgHello = "Barrier%2i%2i";
@@ -296,12 +631,29 @@ mod tests {
             Ok((
                 &[][..],
                 Message::Hello(Hello {
+                    flavour: Flavour::Barrier,
                     server_version: ProtocolVersion { major: 1, minor: 6 }
                 })
             ))
         );
     }
 
+    #[test]
+    fn hello_synergy() {
+        // kMsgHello = "Synergy%2i%2i";
+        const BYTE_ARRAY: [u8; 11] = hex!("53 79 6e 65 72 67 79 00 01 00 08");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Hello(Hello {
+                    flavour: Flavour::Synergy,
+                    server_version: ProtocolVersion { major: 1, minor: 8 }
+                })
+            ))
+        );
+    }
+
     #[test]
     fn command_keep_alive() {
         // kMsgCKeepAlive = "CALV";
@@ -365,6 +717,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn command_enter_negative_position() {
+        // Enter Screen
+        // Screen X: -10
+        // Screen Y: -1080
+        // Sequence Number: 2
+        // Modifier Key Mask: 0
+        const BYTE_ARRAY: [u8; 14] = hex!("43 49 4e 4e ff f6 fb c8 00 00 00 02 00 00");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Command(Command::Enter(Enter {
+                    x: -10,
+                    y: -1080,
+                    sequence_number: 2,
+                    key_modifier_mask: 0,
+                }))
+            ))
+        );
+    }
+
     #[test]
     fn command_leave() {
         let bytes: &[u8] = &hex!("43 4f 55 54")[..];
@@ -374,13 +748,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn command_grab() {
+        // kMsgCClipboard = "CCLP%1i%4i";
+        const BYTE_ARRAY: [u8; 9] = hex!("43 43 4c 50 01 00 00 00 2a");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Command(Command::Grab(Grab {
+                    clipboard: 1,
+                    sequence_number: 42,
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn command_screensaver() {
+        // kMsgCScreenSaver = "CSEC%1i";
+        assert_eq!(
+            message(&hex!("43 53 45 43 01")),
+            Ok((&[][..], Message::Command(Command::Screensaver(true))))
+        );
+        assert_eq!(
+            message(&hex!("43 53 45 43 00")),
+            Ok((&[][..], Message::Command(Command::Screensaver(false))))
+        );
+    }
+
     #[test]
     fn data_options() {
         // kMsgDSetOptions = "DSOP%4I";
         const BYTE_ARRAY: [u8; 8] = hex!("44 53 4f 50 00 00 00 00");
         assert_eq!(
             message(&BYTE_ARRAY),
-            Ok((&[][..], Message::Data(Data::Options(Options {}))))
+            Ok((
+                &[][..],
+                Message::Data(Data::Options(Options { options: vec![] }))
+            ))
+        );
+        const HALF_DUPLEX: [u8; 24] =
+            hex!("44 53 4f 50 00 00 00 04 48 44 43 4c 00 00 00 01 48 44 4e 4c 00 00 00 00");
+        assert_eq!(
+            message(&HALF_DUPLEX),
+            Ok((
+                &[][..],
+                Message::Data(Data::Options(Options {
+                    options: vec![
+                        ServerOption {
+                            id: *b"HDCL",
+                            value: 1
+                        },
+                        ServerOption {
+                            id: *b"HDNL",
+                            value: 0
+                        },
+                    ]
+                }))
+            ))
         );
     }
 
@@ -394,11 +820,210 @@ mod tests {
                 &[][..],
                 Message::Data(Data::Clipboard(Clipboard {
                     clipboard: 1,
-                    mark: 1,
+                    mark: Mark::Start,
                     sequence_number: 0,
+                    data: b"4".to_vec(),
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn error_busy() {
+        // kMsgEBusy = "EBSY";
+        const BYTE_ARRAY: [u8; 4] = hex!("45 42 53 59");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((&[][..], Message::Error(Error::Busy)))
+        );
+    }
+
+    #[test]
+    fn error_unknown() {
+        // kMsgEUnknown = "EUNK";
+        const BYTE_ARRAY: [u8; 4] = hex!("45 55 4e 4b");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((&[][..], Message::Error(Error::Unkown)))
+        );
+    }
+
+    #[test]
+    fn error_bad() {
+        // kMsgEBad = "EBAD";
+        const BYTE_ARRAY: [u8; 4] = hex!("45 42 41 44");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((&[][..], Message::Error(Error::Bad)))
+        );
+    }
+
+    #[test]
+    fn data_clipboard_chunk() {
+        const BYTE_ARRAY: [u8; 17] = hex!("44 43 4c 50 00 00 00 00 07 02 00 00 00 03 61 62 63");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Data(Data::Clipboard(Clipboard {
+                    clipboard: 0,
+                    mark: Mark::Chunk,
+                    sequence_number: 7,
+                    data: b"abc".to_vec(),
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn data_clipboard_invalid_mark() {
+        const BYTE_ARRAY: [u8; 14] = hex!("44 43 4c 50 00 00 00 00 07 04 00 00 00 00");
+        assert!(message(&BYTE_ARRAY).is_err());
+    }
+
+    #[test]
+    fn data_clipboard_legacy() {
+        // kMsgDClipboard = "DCLP%1i%4i%s"; before protocol 1.6
+        const BYTE_ARRAY: [u8; 13] = hex!("44 43 4c 50 00 00 00 00 03 00 00 00 00");
+        let context = context(1, 4);
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context),
+            Ok((
+                &[][..],
+                Message::Data(Data::LegacyClipboard(LegacyClipboard {
+                    clipboard: 0,
+                    sequence_number: 3,
+                    data: Vec::new(),
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn data_clipboard_chunked_needs_1_6() {
+        const BYTE_ARRAY: [u8; 15] = hex!("44 43 4c 50 01 00 00 00 00 01 00 00 00 01 34");
+        let context = context(1, 4);
+        assert!(parse_frame(&BYTE_ARRAY, &context).is_err());
+    }
+
+    #[test]
+    fn data_secure_input() {
+        // kMsgDSecureInputNotification = "SECN%s";
+        const BYTE_ARRAY: [u8; 12] = hex!("53 45 43 4e 00 00 00 04 74 65 72 6d");
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &Context::default()),
+            Ok((
+                &[][..],
+                Message::Data(Data::SecureInput(SecureInput {
+                    app: "term".to_string()
+                }))
+            ))
+        );
+        let context = context(1, 6);
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context),
+            Err(nom::Err::Failure(ParseError::NotSupported(
+                &BYTE_ARRAY[..],
+                Feature::SecureInputNotification
+            )))
+        );
+    }
+
+    #[test]
+    fn data_file_transfer() {
+        // kMsgDFileTransfer = "DFTR%1i%s";
+        const BYTE_ARRAY: [u8; 11] = hex!("44 46 54 52 02 00 00 00 02 68 69");
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 5)),
+            Ok((
+                &[][..],
+                Message::Data(Data::FileTransfer(FileTransfer {
+                    mark: Mark::Chunk,
+                    data: b"hi".to_vec(),
                 }))
             ))
         );
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 4)),
+            Err(nom::Err::Failure(ParseError::NotSupported(
+                &BYTE_ARRAY[..],
+                Feature::FileTransfer
+            )))
+        );
+    }
+
+    #[test]
+    fn data_drag_info() {
+        // kMsgDDragInfo = "DDRG%2i%s";
+        const BYTE_ARRAY: [u8; 27] = hex!(
+            "44 44 52 47 00 02 00 00 00 11 2f 74 6d 70 2f 61 2c 2f 74 6d 70 2f 62 2e 74 78 2c"
+        );
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 6)),
+            Ok((
+                &[][..],
+                Message::Data(Data::DragInfo(DragInfo {
+                    count: 2,
+                    files: vec!["/tmp/a".to_string(), "/tmp/b.tx".to_string()],
+                }))
+            ))
+        );
+    }
+
+    #[test]
+    fn data_key_down_language() {
+        // kMsgDKeyDownLang = "DKDL%2i%2i%2i%s";
+        const BYTE_ARRAY: [u8; 16] = hex!("44 4b 44 4c 00 63 00 02 00 36 00 00 00 02 64 65");
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 8)),
+            Ok((
+                &[][..],
+                Message::Data(Data::KeyDown(Key {
+                    id: 99,
+                    modifier_mask: 2,
+                    button: 54,
+                    language: Some("de".to_string()),
+                }))
+            ))
+        );
+        assert!(parse_frame(&BYTE_ARRAY, &context(1, 7)).is_err());
+    }
+
+    #[test]
+    fn data_languages() {
+        // kMsgDLanguageSynchronisation = "LSYN%s";
+        const BYTE_ARRAY: [u8; 14] = hex!("4c 53 59 4e 00 00 00 06 65 6e 64 65 72 75");
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 8)),
+            Ok((
+                &[][..],
+                Message::Data(Data::Languages(vec![
+                    "en".to_string(),
+                    "de".to_string(),
+                    "ru".to_string()
+                ]))
+            ))
+        );
+        assert_eq!(
+            parse_frame(&BYTE_ARRAY, &context(1, 7)),
+            Err(nom::Err::Failure(ParseError::NotSupported(
+                &BYTE_ARRAY[..],
+                Feature::LanguageSync
+            )))
+        );
+    }
+
+    #[test]
+    fn error_incompatible() {
+        // kMsgEIncompatible = "EICV%2i%2i";
+        const BYTE_ARRAY: [u8; 8] = hex!("45 49 43 56 00 01 00 06");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Error(Error::Incompatible(ProtocolVersion { major: 1, minor: 6 }))
+            ))
+        );
     }
 
     #[test]
@@ -417,6 +1042,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn data_mouse_move_negative_position() {
+        // Mouse Move
+        // X Axis: -10
+        // Y Axis: -1
+        const BYTE_ARRAY: [u8; 8] = hex!("44 4d 4d 56 ff f6 ff ff");
+        assert_eq!(
+            message(&BYTE_ARRAY),
+            Ok((
+                &[][..],
+                Message::Data(Data::MouseMove(MouseMove { x: -10, y: -1 }))
+            ))
+        );
+    }
+
     #[test]
     fn data_mouse_down() {
         let bytes: &[u8] = &hex!("44 4d 44 4e 01")[..];
@@ -450,7 +1090,8 @@ mod tests {
                 Message::Data(Data::KeyDown(Key {
                     id: 99,
                     modifier_mask: 2,
-                    button: 54
+                    button: 54,
+                    language: None,
                 }))
             ))
         );
@@ -471,7 +1112,8 @@ mod tests {
                 Message::Data(Data::KeyUp(Key {
                     id: 99,
                     modifier_mask: 2,
-                    button: 54
+                    button: 54,
+                    language: None,
                 }))
             ))
         );
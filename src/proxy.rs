@@ -0,0 +1,348 @@
+use crate::transport::{split_host_port, DEFAULT_PORT};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Proxy connection failed: {}", source))]
+    Io { source: io::Error },
+    #[snafu(display("Invalid server address {:?}", address))]
+    InvalidAddress { address: String },
+    #[snafu(display("Proxy sent an invalid reply: {}", reason))]
+    InvalidReply { reason: &'static str },
+    #[snafu(display("Proxy accepts none of our authentication methods"))]
+    NoAcceptableMethod {},
+    #[snafu(display("Proxy rejected our username or password"))]
+    AuthenticationFailed {},
+    #[snafu(display(
+        "SOCKS5 proxy username and password can be at most {} bytes each",
+        MAX_CREDENTIAL_LENGTH
+    ))]
+    CredentialsTooLong {},
+    #[snafu(display("Proxy could not connect to the server: {}", socks_reply(*reply)))]
+    ConnectFailed { reply: u8 },
+    #[snafu(display("Proxy could not connect to the server: {}", status))]
+    HttpStatus { status: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// SOCKS5 sends the username and password with a one byte length.
+const MAX_CREDENTIAL_LENGTH: usize = 255;
+
+/// Longest HTTP response header we read before giving up.
+const MAX_HTTP_HEADER: usize = 16 * 1024;
+
+fn socks_reply(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+    /// An HTTP proxy supporting `CONNECT`.
+    Http,
+}
+
+impl ProxyKind {
+    /// Port used when the proxy address names none.
+    pub fn default_port(self) -> u16 {
+        match self {
+            ProxyKind::Socks5 => 1080,
+            ProxyKind::Http => 8080,
+        }
+    }
+}
+
+/// A proxy to reach the server through over TCP.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy, the port defaults to 1080 for SOCKS5 and
+    /// 8080 for HTTP.
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// Written out to keep the password out of logs.
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("kind", &self.kind)
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Proxy {
+    /// Asks the proxy on the other end of `stream` to connect to `address`,
+    /// after which the stream carries the connection to the server.
+    pub async fn handshake<S>(&self, stream: &mut S, address: &str) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (host, port) = split_host_port(address);
+        let port = match port {
+            Some(port) => port.parse().ok().context(InvalidAddress { address })?,
+            None => DEFAULT_PORT,
+        };
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5(stream, host, port).await,
+            ProxyKind::Http => self.http(stream, host, port).await,
+        }
+    }
+
+    /// Checks what can be checked before connecting.
+    pub fn validate(&self) -> Result<()> {
+        if let (ProxyKind::Socks5, Some((username, password))) = (self.kind, self.credentials()) {
+            ensure!(
+                username.len() <= MAX_CREDENTIAL_LENGTH && password.len() <= MAX_CREDENTIAL_LENGTH,
+                CredentialsTooLong {}
+            );
+        }
+        Ok(())
+    }
+
+    fn credentials(&self) -> Option<(&str, &str)> {
+        let username = self.username.as_deref()?;
+        Some((username, self.password.as_deref().unwrap_or("")))
+    }
+
+    async fn socks5<S>(&self, stream: &mut S, host: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let credentials = self.credentials();
+        let greeting: &[u8] = match credentials {
+            Some(_) => &[SOCKS_VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[SOCKS_VERSION, 1, NO_AUTHENTICATION],
+        };
+        stream.write_all(greeting).await.context(Io {})?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await.context(Io {})?;
+        ensure!(
+            choice[0] == SOCKS_VERSION,
+            InvalidReply {
+                reason: "not a SOCKS5 proxy"
+            }
+        );
+        match (choice[1], credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((username, password))) => {
+                authenticate(stream, username, password).await?
+            }
+            (NO_ACCEPTABLE_METHOD, _) => return NoAcceptableMethod {}.fail(),
+            _ => {
+                return InvalidReply {
+                    reason: "authentication method we did not offer",
+                }
+                .fail()
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            // Leave resolving the name to the proxy.
+            Err(_) => {
+                ensure!(
+                    !host.is_empty() && host.len() <= 255,
+                    InvalidAddress { address: host }
+                );
+                request.push(DOMAIN_NAME);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await.context(Io {})?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.context(Io {})?;
+        ensure!(
+            reply[0] == SOCKS_VERSION,
+            InvalidReply {
+                reason: "not a SOCKS5 reply"
+            }
+        );
+        ensure!(reply[1] == 0, ConnectFailed { reply: reply[1] });
+        // Skip the address the proxy bound for us.
+        let length = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await.context(Io {})? as usize,
+            _ => {
+                return InvalidReply {
+                    reason: "unknown address type",
+                }
+                .fail()
+            }
+        };
+        let mut bound = vec![0; length + 2];
+        stream.read_exact(&mut bound).await.context(Io {})?;
+        Ok(())
+    }
+
+    async fn http<S>(&self, stream: &mut S, host: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = self.credentials() {
+            let token = base64(format!("{}:{}", username, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await.context(Io {})?;
+
+        // Read byte by byte, whatever follows the header is the server's.
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            ensure!(
+                header.len() < MAX_HTTP_HEADER,
+                InvalidReply {
+                    reason: "response header too long"
+                }
+            );
+            header.push(stream.read_u8().await.context(Io {})?);
+        }
+        let header = String::from_utf8_lossy(&header);
+        let status = header.lines().next().unwrap_or("").to_string();
+        let mut parts = status.split_whitespace();
+        let version = parts.next().unwrap_or("");
+        let code = parts.next().unwrap_or("");
+        ensure!(
+            version.starts_with("HTTP/"),
+            InvalidReply {
+                reason: "not an HTTP response"
+            }
+        );
+        ensure!(code.starts_with('2'), HttpStatus { status });
+        Ok(())
+    }
+}
+
+async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ensure!(
+        username.len() <= MAX_CREDENTIAL_LENGTH && password.len() <= MAX_CREDENTIAL_LENGTH,
+        CredentialsTooLong {}
+    );
+    let mut request = vec![1, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await.context(Io {})?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.context(Io {})?;
+    ensure!(reply[1] == 0, AuthenticationFailed {});
+    Ok(())
+}
+
+/// Standard base64 with padding, for basic authentication.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credential_lengths() {
+        let proxy = |kind, password: &str| Proxy {
+            kind,
+            address: "proxy".to_string(),
+            username: Some("user".to_string()),
+            password: Some(password.to_string()),
+        };
+        assert!(proxy(ProxyKind::Socks5, &"p".repeat(255))
+            .validate()
+            .is_ok());
+        match proxy(ProxyKind::Socks5, &"p".repeat(256)).validate() {
+            Err(Error::CredentialsTooLong {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(proxy(ProxyKind::Http, &"p".repeat(256)).validate().is_ok());
+    }
+
+    #[test]
+    fn password_not_logged() {
+        let proxy = Proxy {
+            kind: ProxyKind::Socks5,
+            address: "proxy:1080".to_string(),
+            username: Some("user".to_string()),
+            password: Some("hunter2".to_string()),
+        };
+        let debug = format!("{:?}", proxy);
+        assert!(debug.contains("user") && debug.contains("<redacted>"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+    }
+}
@@ -0,0 +1,450 @@
+use crate::parser::Info;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+#[derive(Debug, Snafu, PartialEq)]
+pub enum Error {
+    #[snafu(display("No outputs configured"))]
+    NoOutputs,
+    #[snafu(display("Invalid scale {} for output at {},{}", scale, x, y))]
+    InvalidScale { x: i32, y: i32, scale: f64 },
+    #[snafu(display("Invalid size {}x{} for output at {},{}", width, height, x, y))]
+    InvalidSize {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    },
+    #[snafu(display("Invalid absolute axis maximum {}x{}", x_maximum, y_maximum))]
+    InvalidAbsMaximum { x_maximum: i32, y_maximum: i32 },
+    #[snafu(display(
+        "Screen geometry {},{} {}x{} does not fit the protocol's 16 bit coordinates",
+        x,
+        y,
+        width,
+        height
+    ))]
+    ScreenTooLarge {
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    },
+    #[snafu(display("Screen geometry {}x{} is empty once scaled", width, height))]
+    ScreenEmpty { width: i32, height: i32 },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A monitor as laid out by the compositor.
+///
+/// `x` and `y` are in logical (scaled) compositor coordinates while `width`
+/// and `height` are the physical mode of the output, so a 2880x1620 panel at
+/// 1.5x scaling occupies 1920x1080 of the logical layout.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Output {
+    #[serde(default)]
+    pub x: i32,
+    #[serde(default)]
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl Output {
+    fn logical_width(&self) -> f64 {
+        f64::from(self.width) / self.scale
+    }
+
+    fn logical_height(&self) -> f64 {
+        f64::from(self.height) / self.scale
+    }
+}
+
+/// Maps positions received from the server onto the absolute axes of the
+/// uinput mouse.
+///
+/// The server addresses our screen in the logical coordinates we report in
+/// `DINF`, which is the bounding box of all outputs. The compositor spreads the
+/// absolute axes of the device over that same bounding box, so the transform
+/// only has to rescale from logical pixels to the axis range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transform {
+    x: i16,
+    y: i16,
+    width: i16,
+    height: i16,
+    abs_x_maximum: i32,
+    abs_y_maximum: i32,
+}
+
+impl Transform {
+    /// Creates a transform for `outputs`. When no absolute axis maximum is
+    /// given the axes use one unit per logical pixel.
+    pub fn new(outputs: &[Output], abs_maximum: Option<(i32, i32)>) -> Result<Self> {
+        ensure!(!outputs.is_empty(), NoOutputs);
+        let mut left = f64::MAX;
+        let mut top = f64::MAX;
+        let mut right = f64::MIN;
+        let mut bottom = f64::MIN;
+        for output in outputs {
+            ensure!(
+                output.scale.is_finite() && output.scale > 0.0,
+                InvalidScale {
+                    x: output.x,
+                    y: output.y,
+                    scale: output.scale,
+                }
+            );
+            ensure!(
+                output.width > 0 && output.height > 0,
+                InvalidSize {
+                    x: output.x,
+                    y: output.y,
+                    width: output.width,
+                    height: output.height,
+                }
+            );
+            left = left.min(f64::from(output.x));
+            top = top.min(f64::from(output.y));
+            right = right.max(f64::from(output.x) + output.logical_width());
+            bottom = bottom.max(f64::from(output.y) + output.logical_height());
+        }
+        let x = left.floor() as i32;
+        let y = top.floor() as i32;
+        let width = (right - left).round() as i32;
+        let height = (bottom - top).round() as i32;
+        ensure!(width > 0 && height > 0, ScreenEmpty { width, height });
+        let fits = |v: i32| v >= i32::from(i16::MIN) && v <= i32::from(i16::MAX);
+        ensure!(
+            fits(x)
+                && fits(y)
+                && fits(width)
+                && fits(height)
+                && fits(x + width)
+                && fits(y + height),
+            ScreenTooLarge {
+                x,
+                y,
+                width,
+                height,
+            }
+        );
+        let (abs_x_maximum, abs_y_maximum) = abs_maximum.unwrap_or((width, height));
+        ensure!(
+            abs_x_maximum > 0 && abs_y_maximum > 0,
+            InvalidAbsMaximum {
+                x_maximum: abs_x_maximum,
+                y_maximum: abs_y_maximum,
+            }
+        );
+        Ok(Self {
+            x: x as i16,
+            y: y as i16,
+            width: width as i16,
+            height: height as i16,
+            abs_x_maximum,
+            abs_y_maximum,
+        })
+    }
+
+    /// Top left corner of the screen in logical coordinates.
+    pub fn origin(&self) -> (i16, i16) {
+        (self.x, self.y)
+    }
+
+    /// Size of the screen in logical coordinates.
+    pub fn size(&self) -> (i16, i16) {
+        (self.width, self.height)
+    }
+
+    /// Maximum values of the absolute X and Y axes.
+    pub fn abs_maximum(&self) -> (i32, i32) {
+        (self.abs_x_maximum, self.abs_y_maximum)
+    }
+
+    /// Converts a logical position from the server into absolute axis values,
+    /// clamping positions outside of the screen to its edges.
+    pub fn apply(&self, x: i16, y: i16) -> (i32, i32) {
+        (
+            scale_axis(x, self.x, self.width, self.abs_x_maximum),
+            scale_axis(y, self.y, self.height, self.abs_y_maximum),
+        )
+    }
+
+    /// Clamps a logical position to the last pixel inside the screen.
+    pub fn clamp(&self, x: i16, y: i16) -> (i16, i16) {
+        (
+            x.max(self.x).min(self.x + self.width - 1),
+            y.max(self.y).min(self.y + self.height - 1),
+        )
+    }
+
+    fn center(&self) -> (i16, i16) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+/// The client's screen as the server sees it in `DINF`.
+///
+/// Keeps track of where the client last put the cursor so it can be reported
+/// back accurately, and of geometry changes the server still has to be told
+/// about. After new geometry has been sent mouse moves are ignored until the
+/// server acknowledges it, since they were computed against the old shape.
+#[derive(Debug)]
+pub struct Screen {
+    transform: Transform,
+    jump_zone: i16,
+    cursor: (i16, i16),
+    geometry_changed: bool,
+    awaiting_info_ack: bool,
+}
+
+impl Screen {
+    pub fn new(transform: Transform, jump_zone: i16) -> Self {
+        let cursor = transform.center();
+        Self {
+            transform,
+            jump_zone,
+            cursor,
+            geometry_changed: false,
+            awaiting_info_ack: false,
+        }
+    }
+
+    pub fn transform(&self) -> &Transform {
+        &self.transform
+    }
+
+    /// Size of the jump zone at the screen edges in logical pixels.
+    pub fn jump_zone(&self) -> i16 {
+        self.jump_zone
+    }
+
+    /// Last logical position the cursor was moved to.
+    pub fn cursor(&self) -> (i16, i16) {
+        self.cursor
+    }
+
+    pub fn info(&self) -> Info {
+        let (x, y) = self.transform.origin();
+        let (width, height) = self.transform.size();
+        let (cursor_x, cursor_y) = self.cursor;
+        Info {
+            x,
+            y,
+            width,
+            height,
+            jump_zone: self.jump_zone,
+            cursor_x,
+            cursor_y,
+        }
+    }
+
+    /// Records the cursor at `x`,`y` and returns the absolute axis values to
+    /// inject, or `None` while new geometry is waiting to be acknowledged.
+    pub fn move_cursor(&mut self, x: i16, y: i16) -> Option<(i32, i32)> {
+        if self.awaiting_info_ack {
+            return None;
+        }
+        self.cursor = self.transform.clamp(x, y);
+        Some(self.transform.apply(x, y))
+    }
+
+    /// Replaces the screen geometry, marking it for resending if it changed.
+    pub fn set_geometry(&mut self, transform: Transform, jump_zone: i16) {
+        if transform == self.transform && jump_zone == self.jump_zone {
+            return;
+        }
+        self.cursor = transform.clamp(self.cursor.0, self.cursor.1);
+        self.transform = transform;
+        self.jump_zone = jump_zone;
+        self.geometry_changed = true;
+    }
+
+    /// Returns whether the geometry changed since it was last sent. Mouse
+    /// moves are ignored from here on until `info_acknowledged` is called.
+    pub fn take_geometry_changed(&mut self) -> bool {
+        if !self.geometry_changed {
+            return false;
+        }
+        self.geometry_changed = false;
+        self.awaiting_info_ack = true;
+        true
+    }
+
+    pub fn info_acknowledged(&mut self) {
+        self.awaiting_info_ack = false;
+    }
+}
+
+fn scale_axis(value: i16, origin: i16, length: i16, maximum: i32) -> i32 {
+    let offset = i64::from(value) - i64::from(origin);
+    let scaled = (offset * i64::from(maximum) + i64::from(length) / 2) / i64::from(length);
+    scaled.max(0).min(i64::from(maximum)) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(x: i32, y: i32, width: u32, height: u32, scale: f64) -> Output {
+        Output {
+            x,
+            y,
+            width,
+            height,
+            scale,
+        }
+    }
+
+    #[test]
+    fn single_output_is_identity() {
+        let transform = Transform::new(&[output(0, 0, 1920, 1080, 1.0)], None).unwrap();
+        assert_eq!(transform.origin(), (0, 0));
+        assert_eq!(transform.size(), (1920, 1080));
+        assert_eq!(transform.abs_maximum(), (1920, 1080));
+        assert_eq!(transform.apply(0, 0), (0, 0));
+        assert_eq!(transform.apply(315, 664), (315, 664));
+    }
+
+    #[test]
+    fn fractional_scale_uses_logical_size() {
+        let transform = Transform::new(&[output(0, 0, 2880, 1620, 1.5)], None).unwrap();
+        assert_eq!(transform.size(), (1920, 1080));
+        assert_eq!(transform.apply(960, 540), (960, 540));
+    }
+
+    #[test]
+    fn abs_maximum_rescales_positions() {
+        let transform =
+            Transform::new(&[output(0, 0, 2880, 1620, 1.5)], Some((65535, 65535))).unwrap();
+        assert_eq!(transform.abs_maximum(), (65535, 65535));
+        assert_eq!(transform.apply(0, 0), (0, 0));
+        assert_eq!(transform.apply(960, 540), (32768, 32768));
+        assert_eq!(transform.apply(1920, 1080), (65535, 65535));
+    }
+
+    #[test]
+    fn mixed_scale_outputs_span_bounding_box() {
+        // 4K panel at 2x on the left, 1080p at 1x on the right.
+        let outputs = [
+            output(0, 0, 3840, 2160, 2.0),
+            output(1920, 0, 1920, 1080, 1.0),
+        ];
+        let transform = Transform::new(&outputs, Some((3839, 1079))).unwrap();
+        assert_eq!(transform.size(), (3840, 1080));
+        assert_eq!(transform.apply(1920, 540), (1920, 540));
+        assert_eq!(transform.apply(3840, 1080), (3839, 1079));
+    }
+
+    #[test]
+    fn negative_origin() {
+        let outputs = [
+            output(-1280, -200, 1280, 1024, 1.0),
+            output(0, 0, 1920, 1080, 1.0),
+        ];
+        let transform = Transform::new(&outputs, None).unwrap();
+        assert_eq!(transform.origin(), (-1280, -200));
+        assert_eq!(transform.size(), (3200, 1280));
+        assert_eq!(transform.apply(-1280, -200), (0, 0));
+        assert_eq!(transform.apply(-10, 0), (1270, 200));
+    }
+
+    #[test]
+    fn positions_outside_screen_are_clamped() {
+        let transform = Transform::new(&[output(0, 0, 1920, 1080, 1.0)], None).unwrap();
+        assert_eq!(transform.apply(-10, -10), (0, 0));
+        assert_eq!(transform.apply(5000, 5000), (1920, 1080));
+    }
+
+    #[test]
+    fn screen_tracks_cursor() {
+        let transform =
+            Transform::new(&[output(0, 0, 2880, 1620, 1.5)], Some((65535, 65535))).unwrap();
+        let mut screen = Screen::new(transform, 1);
+        assert_eq!(screen.cursor(), (960, 540));
+        assert_eq!(screen.move_cursor(1920, 1080), Some((65535, 65535)));
+        assert_eq!(screen.cursor(), (1919, 1079));
+        assert_eq!(screen.move_cursor(-10, 10), Some((0, 607)));
+        assert_eq!(screen.cursor(), (0, 10));
+    }
+
+    #[test]
+    fn screen_geometry_change() {
+        let transform = Transform::new(&[output(0, 0, 2560, 1440, 1.0)], None).unwrap();
+        let mut screen = Screen::new(transform.clone(), 0);
+        screen.move_cursor(2000, 1000);
+
+        screen.set_geometry(transform, 0);
+        assert!(!screen.take_geometry_changed());
+
+        let smaller = Transform::new(&[output(0, 0, 1920, 1080, 1.0)], None).unwrap();
+        screen.set_geometry(smaller, 0);
+        assert_eq!(screen.cursor(), (1919, 1000));
+        assert!(screen.take_geometry_changed());
+        assert!(!screen.take_geometry_changed());
+        assert_eq!(screen.move_cursor(10, 10), None);
+        assert_eq!(screen.cursor(), (1919, 1000));
+
+        screen.info_acknowledged();
+        assert_eq!(screen.move_cursor(10, 10), Some((10, 10)));
+        assert_eq!(screen.cursor(), (10, 10));
+    }
+
+    #[test]
+    fn screen_jump_zone_change() {
+        let transform = Transform::new(&[output(0, 0, 1920, 1080, 1.0)], None).unwrap();
+        let mut screen = Screen::new(transform.clone(), 0);
+        screen.set_geometry(transform, 5);
+        assert_eq!(screen.jump_zone(), 5);
+        assert!(screen.take_geometry_changed());
+    }
+
+    #[test]
+    fn invalid_outputs() {
+        assert_eq!(Transform::new(&[], None), Err(Error::NoOutputs));
+        assert_eq!(
+            Transform::new(&[output(0, 0, 1920, 1080, 0.0)], None),
+            Err(Error::InvalidScale {
+                x: 0,
+                y: 0,
+                scale: 0.0
+            })
+        );
+        assert_eq!(
+            Transform::new(&[output(0, 0, 0, 1080, 1.0)], None),
+            Err(Error::InvalidSize {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 1080
+            })
+        );
+        assert_eq!(
+            Transform::new(&[output(0, 0, 1920, 1080, 1.0)], Some((0, 1080))),
+            Err(Error::InvalidAbsMaximum {
+                x_maximum: 0,
+                y_maximum: 1080
+            })
+        );
+        assert!(Transform::new(&[output(32000, 0, 1920, 1080, 1.0)], None).is_err());
+    }
+
+    #[test]
+    fn scaled_to_nothing() {
+        assert_eq!(
+            Transform::new(&[output(0, 0, 1, 1080, 4.0)], None),
+            Err(Error::ScreenEmpty {
+                width: 0,
+                height: 270
+            })
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not run {}: {}", program, source))]
+    Spawn {
+        program: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("{} failed: {}", program, status))]
+    CommandFailed { program: String, status: ExitStatus },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What to do when the server's screensaver starts or stops.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Lock the session with `loginctl lock-session` once the screensaver
+    /// starts, unlocking is left to the user.
+    #[default]
+    Lock,
+    /// Start and stop the screensaver through org.freedesktop.ScreenSaver.
+    Dbus,
+    /// Run the configured shell commands.
+    Command,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Screensaver {
+    /// Follow the server's screensaver, off by default.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub action: Action,
+    /// Shell commands for the `command` action.
+    pub on_command: Option<String>,
+    pub off_command: Option<String>,
+}
+
+impl Screensaver {
+    /// Builds the command for the screensaver starting or stopping, `None`
+    /// when there is nothing to do.
+    fn command(&self, active: bool) -> Option<Command> {
+        match self.action {
+            Action::Lock if active => {
+                let mut command = Command::new("loginctl");
+                command.arg("lock-session");
+                Some(command)
+            }
+            Action::Lock => None,
+            Action::Dbus => {
+                let mut command = Command::new("dbus-send");
+                command.args([
+                    "--session",
+                    "--type=method_call",
+                    "--dest=org.freedesktop.ScreenSaver",
+                    "/org/freedesktop/ScreenSaver",
+                    "org.freedesktop.ScreenSaver.SetActive",
+                ]);
+                command.arg(format!("boolean:{}", active));
+                Some(command)
+            }
+            Action::Command => {
+                let script = if active {
+                    &self.on_command
+                } else {
+                    &self.off_command
+                };
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(script.as_ref()?);
+                Some(command)
+            }
+        }
+    }
+
+    /// Starts the command for the screensaver starting or stopping, `None`
+    /// when there is nothing to do.
+    fn spawn(&self, active: bool) -> Result<Option<(String, Child)>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut command = match self.command(active) {
+            Some(command) => command,
+            None => return Ok(None),
+        };
+        let program = command.get_program().to_string_lossy().into_owned();
+        let child = command.stdin(Stdio::null()).spawn().context(Spawn {
+            program: program.clone(),
+        })?;
+        Ok(Some((program, child)))
+    }
+
+    /// Follows the server's screensaver starting or stopping. Lockers like
+    /// `swaylock` only exit once unlocked, so the command is waited for on
+    /// a thread of its own and failures are only logged.
+    pub fn set_active(&self, active: bool) -> Result<()> {
+        if let Some((program, mut child)) = self.spawn(active)? {
+            thread::spawn(move || match child.wait() {
+                Ok(status) if !status.success() => {
+                    println!("{}", Error::CommandFailed { program, status })
+                }
+                Ok(_) => {}
+                Err(e) => println!("Could not wait for {}: {}", program, e),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<&str> {
+        command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect()
+    }
+
+    fn screensaver(action: Action) -> Screensaver {
+        Screensaver {
+            enabled: true,
+            action,
+            on_command: Some("xset dpms force off".to_string()),
+            off_command: None,
+        }
+    }
+
+    #[test]
+    fn lock() {
+        let screensaver = screensaver(Action::Lock);
+        let command = screensaver.command(true).unwrap();
+        assert_eq!(command.get_program(), "loginctl");
+        assert_eq!(args(&command), ["lock-session"]);
+        assert!(screensaver.command(false).is_none());
+    }
+
+    #[test]
+    fn dbus() {
+        let screensaver = screensaver(Action::Dbus);
+        let command = screensaver.command(false).unwrap();
+        assert_eq!(command.get_program(), "dbus-send");
+        assert_eq!(args(&command).last(), Some(&"boolean:false"));
+    }
+
+    #[test]
+    fn shell_command() {
+        let screensaver = screensaver(Action::Command);
+        let command = screensaver.command(true).unwrap();
+        assert_eq!(args(&command), ["-c", "xset dpms force off"]);
+        assert!(screensaver.command(false).is_none());
+    }
+
+    #[test]
+    fn disabled() {
+        let screensaver = Screensaver {
+            enabled: false,
+            action: Action::Command,
+            on_command: Some("exit 1".to_string()),
+            off_command: None,
+        };
+        assert!(screensaver.set_active(true).is_ok());
+    }
+
+    #[test]
+    fn command_failure() {
+        assert!(screensaver(Action::Command).spawn(false).unwrap().is_none());
+        let mut screensaver = screensaver(Action::Command);
+        screensaver.on_command = Some("exit 3".to_string());
+        let (program, mut child) = screensaver.spawn(true).unwrap().unwrap();
+        assert_eq!(program, "sh");
+        assert_eq!(child.wait().unwrap().code(), Some(3));
+    }
+
+    #[test]
+    fn does_not_wait_for_the_locker() {
+        let mut screensaver = screensaver(Action::Command);
+        screensaver.on_command = Some("sleep 5".to_string());
+        let start = std::time::Instant::now();
+        screensaver.set_active(true).unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,424 @@
+use crate::parser::{Command, Data, Flavour, Hello, Message, ProtocolVersion, Query};
+use snafu::{ensure, Snafu};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Snafu, PartialEq)]
+pub enum Error {
+    #[snafu(display(
+        "Server protocol version {}.{} is not compatible with {}.{}",
+        server_version.major,
+        server_version.minor,
+        CLIENT_VERSION.major,
+        CLIENT_VERSION.minor
+    ))]
+    IncompatibleVersion { server_version: ProtocolVersion },
+    #[snafu(display("Unexpected message while {}: {}", state, message))]
+    UnexpectedMessage { state: State, message: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Newest protocol version the client speaks.
+pub const CLIENT_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 8 };
+
+/// Oldest protocol version the client speaks, 1.3 replaced heartbeats with
+/// keep alives.
+pub const MINIMUM_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 3 };
+
+/// Protocol features that only exist from a certain version on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    /// `DFTR` and `DDRG`.
+    FileTransfer,
+    /// `DCLP` carrying a start, chunk or end mark.
+    ClipboardChunks,
+    /// `SECN`.
+    SecureInputNotification,
+    /// `LSYN` and `DKDL`.
+    LanguageSync,
+}
+
+impl Feature {
+    pub fn version(self) -> ProtocolVersion {
+        match self {
+            Feature::FileTransfer => ProtocolVersion { major: 1, minor: 5 },
+            Feature::ClipboardChunks => ProtocolVersion { major: 1, minor: 6 },
+            Feature::SecureInputNotification => ProtocolVersion { major: 1, minor: 7 },
+            Feature::LanguageSync => ProtocolVersion { major: 1, minor: 8 },
+        }
+    }
+}
+
+/// State negotiated with the server during the hello handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    /// Which hello tag the server used, replies have to use the same one.
+    pub flavour: Flavour,
+    /// Version the server announced in its hello.
+    pub server_version: ProtocolVersion,
+    /// Version both sides speak, the lower of the server's and ours.
+    pub version: ProtocolVersion,
+}
+
+impl Context {
+    pub fn negotiate(hello: &Hello) -> Result<Self> {
+        let server_version = hello.server_version;
+        ensure!(
+            server_version.major == CLIENT_VERSION.major && server_version >= MINIMUM_VERSION,
+            IncompatibleVersion { server_version }
+        );
+        Ok(Self {
+            flavour: hello.flavour,
+            server_version,
+            version: server_version.min(CLIENT_VERSION),
+        })
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.version >= feature.version()
+    }
+}
+
+impl Default for Context {
+    /// Context before the handshake, assuming the server speaks our version.
+    fn default() -> Self {
+        Self {
+            flavour: Flavour::Barrier,
+            server_version: CLIENT_VERSION,
+            version: CLIENT_VERSION,
+        }
+    }
+}
+
+/// How long the server may take from accepting the connection to
+/// acknowledging our screen info.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Where a connection is in its lifetime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Connecting,
+    /// Connected, waiting for the server's hello.
+    AwaitingHello,
+    /// Hello answered, waiting for the server to acknowledge our info.
+    Handshaking,
+    /// Handshake done, the cursor is on another screen.
+    Inactive,
+    /// The cursor is on our screen.
+    Active,
+    Closing,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::Connecting => "connecting",
+            State::AwaitingHello => "awaiting hello",
+            State::Handshaking => "handshaking",
+            State::Inactive => "inactive",
+            State::Active => "active",
+            State::Closing => "closing",
+        })
+    }
+}
+
+/// What to do with a message the server sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Disposition {
+    Handle,
+    /// Valid but out of place, like input while our screen is inactive.
+    Drop,
+}
+
+/// Checks the order of the server's messages.
+#[derive(Debug)]
+pub struct Session {
+    state: State,
+    handshake_timeout: Duration,
+    handshake_deadline: Option<Instant>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new(HANDSHAKE_TIMEOUT)
+    }
+}
+
+fn is_input(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Data(Data::MouseMove(_))
+            | Message::Data(Data::MouseDown(_))
+            | Message::Data(Data::MouseUp(_))
+            | Message::Data(Data::KeyDown(_))
+            | Message::Data(Data::KeyUp(_))
+    )
+}
+
+/// Messages the server sends while handshaking, besides errors.
+fn is_handshake(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Query(Query::Info)
+            | Message::Command(Command::InfoAck)
+            | Message::Command(Command::KeepAlive)
+            | Message::Command(Command::ResetOptions)
+            | Message::Data(Data::Options(_))
+    )
+}
+
+impl Session {
+    pub fn new(handshake_timeout: Duration) -> Self {
+        Self {
+            state: State::Connecting,
+            handshake_timeout,
+            handshake_deadline: None,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The connection is up, the handshake has to finish by `now` plus the
+    /// handshake timeout.
+    pub fn connected(&mut self, now: Instant) {
+        self.state = State::AwaitingHello;
+        self.handshake_deadline = Some(now + self.handshake_timeout);
+    }
+
+    /// When a handshake in progress times out.
+    pub fn handshake_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::AwaitingHello | State::Handshaking => self.handshake_deadline,
+            _ => None,
+        }
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    pub fn close(&mut self) {
+        self.state = State::Closing;
+    }
+
+    /// Moves along with a message from the server, failing when it breaks
+    /// the protocol.
+    pub fn receive(&mut self, message: &Message) -> Result<Disposition> {
+        let unexpected = || {
+            UnexpectedMessage {
+                state: self.state,
+                message: format!("{:?}", message),
+            }
+            .fail()
+        };
+        match (self.state, message) {
+            (State::Closing, _) => Ok(Disposition::Drop),
+            // The client gives up on errors whenever they arrive.
+            (_, Message::Error(_)) => Ok(Disposition::Handle),
+            (State::AwaitingHello, Message::Hello(_)) => {
+                self.state = State::Handshaking;
+                Ok(Disposition::Handle)
+            }
+            (State::Connecting, _) | (State::AwaitingHello, _) | (_, Message::Hello(_)) => {
+                unexpected()
+            }
+            (State::Handshaking, Message::Command(Command::InfoAck)) => {
+                self.state = State::Inactive;
+                Ok(Disposition::Handle)
+            }
+            (State::Handshaking, message) if is_handshake(message) => Ok(Disposition::Handle),
+            (State::Handshaking, Message::Command(Command::Enter(_))) => unexpected(),
+            (State::Handshaking, _) => Ok(Disposition::Drop),
+            (_, Message::Command(Command::Enter(_))) => {
+                self.state = State::Active;
+                Ok(Disposition::Handle)
+            }
+            (State::Active, Message::Command(Command::Leave)) => {
+                self.state = State::Inactive;
+                Ok(Disposition::Handle)
+            }
+            (State::Inactive, Message::Command(Command::Leave)) => Ok(Disposition::Drop),
+            (State::Inactive, message) if is_input(message) => Ok(Disposition::Drop),
+            _ => Ok(Disposition::Handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Enter, Error as ParserError, MouseMove};
+
+    fn version(major: u16, minor: u16) -> ProtocolVersion {
+        ProtocolVersion { major, minor }
+    }
+
+    fn hello(major: u16, minor: u16) -> Hello {
+        Hello {
+            flavour: Flavour::Barrier,
+            server_version: version(major, minor),
+        }
+    }
+
+    #[test]
+    fn negotiate_older_server() {
+        let context = Context::negotiate(&hello(1, 4)).unwrap();
+        assert_eq!(context.version, version(1, 4));
+        assert!(!context.supports(Feature::FileTransfer));
+        assert!(!context.supports(Feature::ClipboardChunks));
+    }
+
+    #[test]
+    fn negotiate_same_version() {
+        let context =
+            Context::negotiate(&hello(CLIENT_VERSION.major, CLIENT_VERSION.minor)).unwrap();
+        assert_eq!(context.version, CLIENT_VERSION);
+        assert!(context.supports(Feature::SecureInputNotification));
+        assert!(context.supports(Feature::LanguageSync));
+    }
+
+    #[test]
+    fn negotiate_newer_server() {
+        let context = Context::negotiate(&hello(1, 9)).unwrap();
+        assert_eq!(context.server_version, version(1, 9));
+        assert_eq!(context.version, CLIENT_VERSION);
+    }
+
+    #[test]
+    fn negotiate_records_flavour() {
+        let context = Context::negotiate(&Hello {
+            flavour: Flavour::Synergy,
+            server_version: version(1, 6),
+        })
+        .unwrap();
+        assert_eq!(context.flavour, Flavour::Synergy);
+    }
+
+    #[test]
+    fn negotiate_incompatible() {
+        assert_eq!(
+            Context::negotiate(&hello(1, 2)),
+            Err(Error::IncompatibleVersion {
+                server_version: version(1, 2)
+            })
+        );
+        assert_eq!(
+            Context::negotiate(&hello(2, 0)),
+            Err(Error::IncompatibleVersion {
+                server_version: version(2, 0)
+            })
+        );
+    }
+
+    fn enter() -> Message {
+        Message::Command(Command::Enter(Enter {
+            x: 0,
+            y: 0,
+            sequence_number: 1,
+            key_modifier_mask: 0,
+        }))
+    }
+
+    fn mouse_move() -> Message {
+        Message::Data(Data::MouseMove(MouseMove { x: 1, y: 2 }))
+    }
+
+    fn handshaken() -> Session {
+        let mut session = Session::default();
+        session.connected(Instant::now());
+        session.receive(&Message::Hello(hello(1, 8))).unwrap();
+        session
+            .receive(&Message::Command(Command::InfoAck))
+            .unwrap();
+        session
+    }
+
+    #[test]
+    fn handshake() {
+        let mut session = Session::new(Duration::from_secs(5));
+        assert_eq!(session.state(), State::Connecting);
+        assert_eq!(session.handshake_deadline(), None);
+        let now = Instant::now();
+        session.connected(now);
+        assert_eq!(session.state(), State::AwaitingHello);
+        assert_eq!(
+            session.handshake_deadline(),
+            Some(now + Duration::from_secs(5))
+        );
+        assert_eq!(
+            session.receive(&Message::Hello(hello(1, 8))),
+            Ok(Disposition::Handle)
+        );
+        assert_eq!(session.state(), State::Handshaking);
+        assert_eq!(
+            session.receive(&Message::Query(Query::Info)),
+            Ok(Disposition::Handle)
+        );
+        assert_eq!(session.receive(&mouse_move()), Ok(Disposition::Drop));
+        assert_eq!(
+            session.receive(&Message::Command(Command::InfoAck)),
+            Ok(Disposition::Handle)
+        );
+        assert_eq!(session.state(), State::Inactive);
+        assert_eq!(session.handshake_deadline(), None);
+    }
+
+    #[test]
+    fn hello_first() {
+        let mut session = Session::default();
+        session.connected(Instant::now());
+        match session.receive(&Message::Query(Query::Info)) {
+            Err(Error::UnexpectedMessage { state, .. }) => assert_eq!(state, State::AwaitingHello),
+            result => panic!("unexpected result {:?}", result),
+        }
+        session.connected(Instant::now());
+        assert_eq!(
+            session.receive(&Message::Error(ParserError::Busy)),
+            Ok(Disposition::Handle)
+        );
+    }
+
+    #[test]
+    fn enter_and_leave() {
+        let mut session = handshaken();
+        assert_eq!(session.receive(&mouse_move()), Ok(Disposition::Drop));
+        assert_eq!(
+            session.receive(&Message::Command(Command::Leave)),
+            Ok(Disposition::Drop)
+        );
+        assert_eq!(session.receive(&enter()), Ok(Disposition::Handle));
+        assert_eq!(session.state(), State::Active);
+        assert_eq!(session.receive(&mouse_move()), Ok(Disposition::Handle));
+        assert_eq!(
+            session.receive(&Message::Command(Command::Leave)),
+            Ok(Disposition::Handle)
+        );
+        assert_eq!(session.state(), State::Inactive);
+        assert_eq!(
+            session.receive(&Message::Command(Command::KeepAlive)),
+            Ok(Disposition::Handle)
+        );
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut session = handshaken();
+        assert!(session.receive(&Message::Hello(hello(1, 8))).is_err());
+
+        let mut session = Session::default();
+        session.connected(Instant::now());
+        session.receive(&Message::Hello(hello(1, 8))).unwrap();
+        assert!(session.receive(&enter()).is_err());
+    }
+
+    #[test]
+    fn closing_drops_everything() {
+        let mut session = handshaken();
+        session.close();
+        assert_eq!(session.receive(&enter()), Ok(Disposition::Drop));
+        assert_eq!(session.state(), State::Closing);
+    }
+}
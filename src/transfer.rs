@@ -0,0 +1,407 @@
+use crate::parser::{DragInfo, FileTransfer, Mark};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid file transfer size {:?}", size))]
+    InvalidSize { size: String },
+    #[snafu(display("File transfer chunk without a transfer in progress"))]
+    UnexpectedChunk {},
+    #[snafu(display(
+        "File transfer size mismatch, expected {} bytes but received {}",
+        expected,
+        actual
+    ))]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[snafu(display("Could not create {}: {}", path.display(), source))]
+    CreateFile { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not write {}: {}", path.display(), source))]
+    WriteFile { path: PathBuf, source: io::Error },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Longest file name most Linux file systems accept, in bytes.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Room kept free in received names for the dot and `.part` of partial
+/// files and the numbering of copies.
+const SUFFIX_ROOM: usize = ".".len() + " (99999)".len() + ".part".len();
+
+/// Copies of one name we number before giving up.
+const MAX_COPIES: u32 = 99_999;
+
+/// Name for files the server did not announce a usable name for.
+const FALLBACK_NAME: &str = "barrier-download";
+
+/// Turns a path from the server into a file name that stays inside the
+/// download directory, `None` when nothing usable is left.
+pub fn safe_file_name(path: &str) -> Option<String> {
+    // Windows servers send backslash separated paths.
+    let name = path.rsplit(&['/', '\\'][..]).next()?;
+    let mut name: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string();
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        return None;
+    }
+    let limit = MAX_NAME_LENGTH - SUFFIX_ROOM;
+    if name.len() > limit {
+        // Keep a short extension, shortening the rest of the name.
+        match name.rfind('.') {
+            Some(dot) if dot > 0 && name.len() - dot <= limit / 2 => {
+                let extension = name.split_off(dot);
+                truncate(&mut name, limit - extension.len());
+                name.push_str(&extension);
+            }
+            _ => truncate(&mut name, limit),
+        }
+    }
+    Some(name)
+}
+
+/// Cuts `name` to at most `length` bytes without splitting a character.
+fn truncate(name: &mut String, length: usize) {
+    let mut end = length.min(name.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name.truncate(end);
+}
+
+/// Creates a file in `dir` for `name` that did not exist before, numbering
+/// the name like `notes (1).txt` when needed.
+fn create_unused(dir: &Path, name: &str) -> Result<(PathBuf, File)> {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    for n in 0..=MAX_COPIES {
+        let path = match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{} ({}){}", stem, n, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(source) => return Err(Error::CreateFile { path, source }),
+        }
+    }
+    Err(Error::CreateFile {
+        path: dir.join(name),
+        source: io::Error::new(io::ErrorKind::AlreadyExists, "too many copies"),
+    })
+}
+
+/// How far a transfer has come.
+#[derive(Debug, PartialEq)]
+pub enum Progress {
+    Started {
+        name: String,
+        size: u64,
+    },
+    /// Reported every tenth of the file.
+    Received {
+        name: String,
+        percent: u8,
+    },
+    Finished {
+        path: PathBuf,
+        size: u64,
+    },
+}
+
+#[derive(Debug)]
+struct Download {
+    name: String,
+    /// Partial file, renamed once the transfer completes.
+    part: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    reported: u8,
+}
+
+/// Writes files dragged onto our screen into a download directory.
+///
+/// The server announces the dragged files with `DDRG`, then streams the
+/// file with `DFTR` chunks: a start holding its size, data chunks and an end.
+#[derive(Debug)]
+pub struct Receiver {
+    dir: PathBuf,
+    names: VecDeque<String>,
+    download: Option<Download>,
+}
+
+impl Receiver {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            names: VecDeque::new(),
+            download: None,
+        }
+    }
+
+    /// Remembers the names of newly dragged files for the transfers that
+    /// follow.
+    pub fn drag(&mut self, info: DragInfo) {
+        self.names = info
+            .files
+            .iter()
+            .map(|path| safe_file_name(path).unwrap_or_else(|| FALLBACK_NAME.to_string()))
+            .collect();
+    }
+
+    pub fn push(&mut self, chunk: FileTransfer) -> Result<Option<Progress>> {
+        match chunk.mark {
+            Mark::Start => self.start(&chunk.data).map(Some),
+            Mark::Chunk => self.write(&chunk.data),
+            Mark::End => self.finish().map(Some),
+        }
+    }
+
+    fn start(&mut self, size: &[u8]) -> Result<Progress> {
+        self.abort();
+        let size = String::from_utf8_lossy(size);
+        let size = size.trim().parse::<u64>().ok().context(InvalidSize {
+            size: size.to_string(),
+        })?;
+        let name = self
+            .names
+            .pop_front()
+            .unwrap_or_else(|| FALLBACK_NAME.to_string());
+        fs::create_dir_all(&self.dir).context(CreateFile { path: &self.dir })?;
+        let (part, file) = create_unused(&self.dir, &format!(".{}.part", name))?;
+        self.download = Some(Download {
+            name: name.clone(),
+            part,
+            file,
+            size,
+            received: 0,
+            reported: 0,
+        });
+        Ok(Progress::Started { name, size })
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<Option<Progress>> {
+        let download = self.download.as_mut().context(UnexpectedChunk {})?;
+        let received = download.received + data.len() as u64;
+        if received > download.size {
+            let expected = download.size;
+            self.abort();
+            return SizeMismatch {
+                expected,
+                actual: received,
+            }
+            .fail();
+        }
+        if let Err(source) = download.file.write_all(data) {
+            let path = download.part.clone();
+            self.abort();
+            return Err(Error::WriteFile { path, source });
+        }
+        download.received = received;
+        let percent = (received * 100 / download.size.max(1)) as u8;
+        if percent / 10 > download.reported / 10 && received < download.size {
+            download.reported = percent;
+            return Ok(Some(Progress::Received {
+                name: download.name.clone(),
+                percent,
+            }));
+        }
+        Ok(None)
+    }
+
+    fn finish(&mut self) -> Result<Progress> {
+        let download = self.download.take().context(UnexpectedChunk {})?;
+        if download.received != download.size {
+            let _ = fs::remove_file(&download.part);
+            return SizeMismatch {
+                expected: download.size,
+                actual: download.received,
+            }
+            .fail();
+        }
+        // Claim the name first, so the rename only ever replaces our own
+        // empty file.
+        let (path, _) = create_unused(&self.dir, &download.name)?;
+        if let Err(source) = fs::rename(&download.part, &path) {
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(&download.part);
+            return Err(Error::WriteFile { path, source });
+        }
+        Ok(Progress::Finished {
+            path,
+            size: download.size,
+        })
+    }
+
+    /// Drops a transfer in progress along with its partial file.
+    fn abort(&mut self) {
+        if let Some(download) = self.download.take() {
+            let _ = fs::remove_file(download.part);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("barrier-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chunk(mark: Mark, data: &[u8]) -> FileTransfer {
+        FileTransfer {
+            mark,
+            data: data.to_vec(),
+        }
+    }
+
+    fn drag(files: &[&str]) -> DragInfo {
+        DragInfo {
+            count: files.len() as u16,
+            files: files.iter().map(|file| file.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn safe_file_names() {
+        assert_eq!(
+            safe_file_name("/home/me/notes.txt"),
+            Some("notes.txt".to_string())
+        );
+        assert_eq!(
+            safe_file_name("C:\\Users\\me\\a b.png"),
+            Some("a b.png".to_string())
+        );
+        assert_eq!(
+            safe_file_name("../../etc/passwd"),
+            Some("passwd".to_string())
+        );
+        assert_eq!(
+            safe_file_name("evil\n\u{7}name"),
+            Some("evilname".to_string())
+        );
+        assert_eq!(safe_file_name("/tmp/.."), None);
+        assert_eq!(safe_file_name("/tmp/"), None);
+        assert_eq!(
+            safe_file_name(&"x".repeat(300)).unwrap().len(),
+            MAX_NAME_LENGTH - SUFFIX_ROOM
+        );
+        let long = format!("{}.tar.gz", "é".repeat(150));
+        let name = safe_file_name(&long).unwrap();
+        assert!(name.len() <= MAX_NAME_LENGTH - SUFFIX_ROOM);
+        assert!(name.starts_with('é') && name.ends_with("é.gz"));
+    }
+
+    #[test]
+    fn receive_longest_name() {
+        let dir = temp_dir("long");
+        let name = format!("{}x.txt", "é".repeat(125));
+        assert_eq!(name.len(), MAX_NAME_LENGTH);
+        let mut receiver = Receiver::new(dir.clone());
+        for copy in &["", " (1)"] {
+            receiver.drag(drag(&[&name]));
+            receiver.push(chunk(Mark::Start, b"1")).unwrap();
+            receiver.push(chunk(Mark::Chunk, b"1")).unwrap();
+            match receiver.push(chunk(Mark::End, b"")).unwrap() {
+                Some(Progress::Finished { path, .. }) => {
+                    let received = path.file_name().unwrap().to_str().unwrap();
+                    assert!(received.ends_with(&format!("é{}.txt", copy)));
+                    assert_eq!(fs::read(&path).unwrap(), b"1");
+                }
+                progress => panic!("unexpected progress {:?}", progress),
+            }
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn receive_file() {
+        let dir = temp_dir("receive");
+        let mut receiver = Receiver::new(dir.clone());
+        receiver.drag(drag(&["/home/me/notes.txt"]));
+        assert_eq!(
+            receiver.push(chunk(Mark::Start, b"10")).unwrap(),
+            Some(Progress::Started {
+                name: "notes.txt".to_string(),
+                size: 10
+            })
+        );
+        assert_eq!(
+            receiver.push(chunk(Mark::Chunk, b"01234")).unwrap(),
+            Some(Progress::Received {
+                name: "notes.txt".to_string(),
+                percent: 50
+            })
+        );
+        assert_eq!(
+            receiver.push(chunk(Mark::Chunk, b"56")).unwrap(),
+            Some(Progress::Received {
+                name: "notes.txt".to_string(),
+                percent: 70
+            })
+        );
+        assert_eq!(receiver.push(chunk(Mark::Chunk, b"789")).unwrap(), None);
+        let path = dir.join("notes.txt");
+        assert_eq!(
+            receiver.push(chunk(Mark::End, b"")).unwrap(),
+            Some(Progress::Finished {
+                path: path.clone(),
+                size: 10
+            })
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A second file of the same name does not replace the first.
+        receiver.drag(drag(&["notes.txt"]));
+        receiver.push(chunk(Mark::Start, b"0")).unwrap();
+        match receiver.push(chunk(Mark::End, b"")).unwrap() {
+            Some(Progress::Finished { path, .. }) => {
+                assert_eq!(path, dir.join("notes (1).txt"))
+            }
+            progress => panic!("unexpected progress {:?}", progress),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_mismatch_removes_partial_file() {
+        let dir = temp_dir("mismatch");
+        let mut receiver = Receiver::new(dir.clone());
+        receiver.push(chunk(Mark::Start, b"2")).unwrap();
+        match receiver.push(chunk(Mark::Chunk, b"abc")) {
+            Err(Error::SizeMismatch { expected, actual }) => assert_eq!((expected, actual), (2, 3)),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        match receiver.push(chunk(Mark::End, b"")) {
+            Err(Error::UnexpectedChunk {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_size() {
+        let mut receiver = Receiver::new(temp_dir("invalid"));
+        match receiver.push(chunk(Mark::Start, b"big")) {
+            Err(Error::InvalidSize { size }) => assert_eq!(size, "big"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use crate::connect;
+use crate::mdns;
+use crate::proxy::{self, Proxy};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::time::{timeout_at, Instant};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not connect to {}: {}", address, source))]
+    ConnectTcp { address: String, source: io::Error },
+    #[snafu(display("Timed out reaching {} through the proxy after {:?}", address, timeout))]
+    ConnectTimeout { address: String, timeout: Duration },
+    #[snafu(display("Could not reach {} through the proxy: {}", address, source))]
+    ProxyHandshake {
+        address: String,
+        source: proxy::Error,
+    },
+    #[snafu(display("Could not connect to {}: {}", path.display(), source))]
+    ConnectUnix { path: PathBuf, source: io::Error },
+    #[snafu(display("Could not take over stdout: {}", source))]
+    RedirectStdout { source: nix::Error },
+    #[snafu(display("Could not run proxy command {:?}: {}", command, source))]
+    SpawnProxy { command: String, source: io::Error },
+    #[snafu(display("The unix transport needs a socket path"))]
+    MissingSocket {},
+    #[snafu(display("The proxy transport needs a proxy command"))]
+    MissingProxyCommand {},
+    #[snafu(display("A proxy only works with the tcp transport"))]
+    ProxyNeedsTcp {},
+    #[snafu(display("Invalid proxy: {}", source))]
+    InvalidProxy { source: proxy::Error },
+    #[snafu(display(
+        "{:?} is looked up over mDNS, which needs a direct tcp connection",
+        address
+    ))]
+    DiscoveryNeedsTcp { address: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Port Barrier servers listen on.
+pub const DEFAULT_PORT: u16 = 24800;
+
+/// How to reach the server.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    /// A unix domain socket, like one forwarded with `ssh -L`.
+    Unix,
+    /// Our own stdin and stdout, for running as an ssh remote command.
+    Stdio,
+    /// The stdin and stdout of a command, like ssh's `ProxyCommand`.
+    Proxy,
+}
+
+/// A connection to the server over any transport.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    Tcp {
+        address: String,
+        /// SOCKS5 or HTTP proxy to connect through.
+        proxy: Option<Proxy>,
+        /// Limit for resolving, connecting and the proxy handshake together.
+        connect_timeout: Duration,
+    },
+    Unix(PathBuf),
+    Stdio,
+    /// Shell command with `%h` and `%p` already replaced.
+    Proxy(String),
+}
+
+/// Splits `host:port`, `[v6]:port` or a bare host, `None` when there is no
+/// port.
+pub fn split_host_port(address: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = address.strip_prefix('[') {
+        if let Some((host, port)) = rest.split_once(']') {
+            return (host, port.strip_prefix(':'));
+        }
+    }
+    match address.rsplit_once(':') {
+        // More than one colon is a bare IPv6 address.
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (address, None),
+    }
+}
+
+/// Replaces `%h` and `%p` with the server's host and port and `%%` with a
+/// percent sign, like ssh does for `ProxyCommand`.
+pub fn expand_proxy_command(template: &str, address: &str) -> String {
+    let (host, port) = split_host_port(address);
+    let port = port.map_or_else(|| DEFAULT_PORT.to_string(), str::to_string);
+    let mut command = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            command.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => command.push_str(host),
+            Some('p') => command.push_str(&port),
+            Some('%') => command.push('%'),
+            Some(other) => {
+                command.push('%');
+                command.push(other);
+            }
+            None => command.push('%'),
+        }
+    }
+    command
+}
+
+/// Whether resolving `address` starts with an mDNS query.
+fn uses_mdns(address: &str) -> bool {
+    address.eq_ignore_ascii_case(connect::AUTO) || mdns::is_local(split_host_port(address).0)
+}
+
+impl Transport {
+    pub fn new(
+        kind: TransportKind,
+        address: &str,
+        socket: Option<&Path>,
+        proxy_command: Option<&str>,
+        proxy: Option<&Proxy>,
+        connect_timeout: Duration,
+    ) -> Result<Self> {
+        ensure!(
+            proxy.is_none() || kind == TransportKind::Tcp,
+            ProxyNeedsTcp {}
+        );
+        if let Some(proxy) = proxy {
+            proxy.validate().context(InvalidProxy {})?;
+        }
+        // Whatever sits in between resolves the address itself.
+        let proxied = proxy.is_some() || kind == TransportKind::Proxy;
+        ensure!(
+            !proxied || !uses_mdns(address),
+            DiscoveryNeedsTcp { address }
+        );
+        Ok(match kind {
+            TransportKind::Tcp => Transport::Tcp {
+                address: address.to_string(),
+                proxy: proxy.cloned(),
+                connect_timeout,
+            },
+            TransportKind::Unix => Transport::Unix(socket.context(MissingSocket {})?.to_path_buf()),
+            TransportKind::Stdio => Transport::Stdio,
+            TransportKind::Proxy => Transport::Proxy(expand_proxy_command(
+                proxy_command.context(MissingProxyCommand {})?,
+                address,
+            )),
+        })
+    }
+
+    pub async fn connect(&self) -> Result<Box<dyn Stream>> {
+        match self {
+            Transport::Tcp {
+                address,
+                proxy: None,
+                connect_timeout,
+            } => {
+                let deadline = Instant::now() + *connect_timeout;
+                let stream = connect::connect(address, DEFAULT_PORT, deadline)
+                    .await
+                    .context(ConnectTcp { address })?;
+                Ok(Box::new(stream))
+            }
+            Transport::Tcp {
+                address,
+                proxy: Some(proxy),
+                connect_timeout,
+            } => {
+                let deadline = Instant::now() + *connect_timeout;
+                let mut stream =
+                    connect::connect(&proxy.address, proxy.kind.default_port(), deadline)
+                        .await
+                        .context(ConnectTcp {
+                            address: &proxy.address,
+                        })?;
+                timeout_at(deadline, proxy.handshake(&mut stream, address))
+                    .await
+                    .ok()
+                    .context(ConnectTimeout {
+                        address,
+                        timeout: *connect_timeout,
+                    })?
+                    .context(ProxyHandshake { address })?;
+                Ok(Box::new(stream))
+            }
+            Transport::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .context(ConnectUnix { path })?;
+                Ok(Box::new(stream))
+            }
+            Transport::Stdio => Ok(Box::new(stdio()?)),
+            Transport::Proxy(command) => Ok(Box::new(ProxyStream::spawn(command)?)),
+        }
+    }
+}
+
+/// Where protocol output goes once taken from stdout.
+static PROTOCOL_OUT: OnceLock<RawFd> = OnceLock::new();
+
+/// Keeps stdout for the stdio transport. Log output goes to stdout, so
+/// stdout moves to a new descriptor for the protocol and the old one points
+/// at stderr from then on. Call this before logging anything, only the first
+/// call does something.
+pub fn take_stdout() -> Result<()> {
+    protocol_out().map(drop)
+}
+
+fn protocol_out() -> Result<RawFd> {
+    if let Some(&out) = PROTOCOL_OUT.get() {
+        return Ok(out);
+    }
+    let out = nix::unistd::dup(1).context(RedirectStdout {})?;
+    nix::unistd::dup2(2, 1).context(RedirectStdout {})?;
+    Ok(*PROTOCOL_OUT.get_or_init(|| out))
+}
+
+/// Our stdin and stdout, later connections reuse the moved stdout.
+fn stdio() -> Result<Duplex<tokio::io::Stdin, tokio::fs::File>> {
+    // Each connection closes its own copy.
+    let out = nix::unistd::dup(protocol_out()?).context(RedirectStdout {})?;
+    // SAFETY: `out` is the fresh result of the dup above and only this
+    // `File` owns it.
+    let out = unsafe { File::from_raw_fd(out) };
+    Ok(Duplex {
+        reader: tokio::io::stdin(),
+        writer: tokio::fs::File::from_std(out),
+    })
+}
+
+/// A reader and a writer used as one stream.
+#[derive(Debug)]
+pub struct Duplex<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Duplex<R, W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Duplex<R, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// Talks to the server through a command's stdin and stdout, the command is
+/// killed once the stream is dropped.
+#[derive(Debug)]
+pub struct ProxyStream {
+    _child: Child,
+    io: Duplex<ChildStdout, ChildStdin>,
+}
+
+impl ProxyStream {
+    pub fn spawn(command: &str) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context(SpawnProxy { command })?;
+        let stdout = child.stdout.take().unwrap();
+        let stdin = child.stdin.take().unwrap();
+        Ok(Self {
+            _child: child,
+            io: Duplex {
+                reader: stdout,
+                writer: stdin,
+            },
+        })
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyKind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixListener;
+
+    #[test]
+    fn host_and_port() {
+        assert_eq!(split_host_port("server:24801"), ("server", Some("24801")));
+        assert_eq!(split_host_port("server"), ("server", None));
+        assert_eq!(split_host_port("[::1]:24800"), ("::1", Some("24800")));
+        assert_eq!(split_host_port("[::1]"), ("::1", None));
+        assert_eq!(split_host_port("fe80::1"), ("fe80::1", None));
+    }
+
+    #[test]
+    fn proxy_command_placeholders() {
+        assert_eq!(
+            expand_proxy_command("ssh -W %h:%p bastion", "desk.lan:24801"),
+            "ssh -W desk.lan:24801 bastion"
+        );
+        assert_eq!(
+            expand_proxy_command("nc %h %p # 100%% %x %", "desk.lan"),
+            "nc desk.lan 24800 # 100% %x %"
+        );
+    }
+
+    #[test]
+    fn transport_from_config() {
+        assert_eq!(
+            Transport::new(
+                TransportKind::Proxy,
+                "a:1",
+                None,
+                Some("nc %h %p"),
+                None,
+                Duration::ZERO
+            )
+            .unwrap(),
+            Transport::Proxy("nc a 1".to_string())
+        );
+        match Transport::new(TransportKind::Unix, "a:1", None, None, None, Duration::ZERO) {
+            Err(Error::MissingSocket {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        let proxy = Proxy {
+            kind: ProxyKind::Socks5,
+            address: "proxy:1080".to_string(),
+            username: None,
+            password: None,
+        };
+        match Transport::new(
+            TransportKind::Proxy,
+            "a:1",
+            None,
+            Some("nc %h %p"),
+            Some(&proxy),
+            Duration::ZERO,
+        ) {
+            Err(Error::ProxyNeedsTcp {}) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+        for address in ["auto", "desk.local:24801"] {
+            match Transport::new(
+                TransportKind::Tcp,
+                address,
+                None,
+                None,
+                Some(&proxy),
+                Duration::ZERO,
+            ) {
+                Err(Error::DiscoveryNeedsTcp { .. }) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+        assert!(
+            Transport::new(TransportKind::Tcp, "auto", None, None, None, Duration::ZERO).is_ok()
+        );
+        let proxy = Proxy {
+            username: Some("u".repeat(256)),
+            ..proxy
+        };
+        match Transport::new(
+            TransportKind::Tcp,
+            "a:1",
+            None,
+            None,
+            Some(&proxy),
+            Duration::ZERO,
+        ) {
+            Err(Error::InvalidProxy {
+                source: proxy::Error::CredentialsTooLong {},
+            }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_command() {
+        let mut stream = Transport::Proxy("cat".to_string()).connect().await.unwrap();
+        stream.write_all(b"CALV").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"CALV");
+    }
+
+    #[tokio::test]
+    async fn unix_socket() {
+        let path = std::env::temp_dir().join(format!("barrier-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let transport = Transport::Unix(path.clone());
+        let (stream, accepted) = tokio::join!(transport.connect(), listener.accept());
+        let mut stream = stream.unwrap();
+        let (mut server, _) = accepted.unwrap();
+        server.write_all(b"QINF").await.unwrap();
+        let mut query = [0; 4];
+        stream.read_exact(&mut query).await.unwrap();
+        assert_eq!(&query, b"QINF");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
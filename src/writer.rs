@@ -0,0 +1,154 @@
+use crate::encoder::encode;
+use crate::parser::Message;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Prefixes an encoded message with its length.
+pub fn frame(message: &Message) -> Vec<u8> {
+    let message = encode(message);
+    let mut frame = Vec::with_capacity(4 + message.len());
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&message);
+    frame
+}
+
+/// Owns the write half of the connection and writes queued messages from a
+/// single task, so frames go out whole and in order however little the
+/// socket accepts at a time.
+#[derive(Debug)]
+pub struct Writer {
+    queue: UnboundedSender<Message>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl Writer {
+    pub fn spawn<W>(write: W) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (queue, messages) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_messages(write, messages));
+        Self { queue, task }
+    }
+
+    /// Queues a message, which is dropped when writing failed already.
+    pub fn send(&self, message: Message) {
+        // Fails only once the task stopped, which `stopped` reports.
+        let _ = self.queue.send(message);
+    }
+
+    pub fn send_all(&self, messages: Vec<Message>) {
+        for message in messages {
+            self.send(message);
+        }
+    }
+
+    /// Resolves once writing failed.
+    pub async fn stopped(&mut self) -> io::Result<()> {
+        join(&mut self.task).await
+    }
+
+    /// Writes what is still queued and closes the write half, giving up and
+    /// dropping it after `timeout` when the other end stopped reading.
+    pub async fn finish(self, timeout: Duration) -> io::Result<()> {
+        let Writer { queue, mut task } = self;
+        drop(queue);
+        match tokio::time::timeout(timeout, join(&mut task)).await {
+            Ok(result) => result,
+            Err(_) => {
+                task.abort();
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the server stopped reading",
+                ))
+            }
+        }
+    }
+}
+
+async fn join(task: &mut JoinHandle<io::Result<()>>) -> io::Result<()> {
+    match task.await {
+        Ok(result) => result,
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+async fn write_messages<W>(mut write: W, mut messages: UnboundedReceiver<Message>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(message) = messages.recv().await {
+        println!("send: {:?}", message);
+        write.write_all(&frame(&message)).await?;
+    }
+    write.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Clipboard, Command, Data, Mark};
+    use hex_literal::hex;
+    use tokio::io::{duplex, AsyncReadExt};
+
+    fn clipboard(data: &[u8]) -> Message {
+        Message::Data(Data::Clipboard(Clipboard {
+            clipboard: 0,
+            sequence_number: 1,
+            mark: Mark::Chunk,
+            data: data.to_vec(),
+        }))
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(
+            frame(&Message::Command(Command::KeepAlive)),
+            hex!("00 00 00 04 43 41 4c 56")
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_stay_whole_and_ordered() {
+        // A tiny pipe forces partial writes.
+        let (write, mut read) = duplex(7);
+        let writer = Writer::spawn(write);
+        let messages: Vec<Message> = (0..20u8)
+            .map(|n| clipboard(&vec![n; 100 + n as usize]))
+            .collect();
+        let expected: Vec<u8> = messages.iter().flat_map(frame).collect();
+        writer.send_all(messages);
+        writer.send(Message::Command(Command::KeepAlive));
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            read.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        writer.finish(Duration::from_secs(5)).await.unwrap();
+        let received = reader.await.unwrap();
+        assert_eq!(&received[..expected.len()], &expected[..]);
+        assert_eq!(&received[expected.len()..], hex!("00 00 00 04 43 41 4c 56"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finish_gives_up_on_a_stalled_reader() {
+        let (write, _read) = duplex(16);
+        let writer = Writer::spawn(write);
+        writer.send(clipboard(&[0; 64]));
+        let error = writer.finish(Duration::from_secs(3)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn write_errors_are_reported() {
+        let (write, read) = duplex(64);
+        drop(read);
+        let mut writer = Writer::spawn(write);
+        writer.send(Message::Command(Command::KeepAlive));
+        let error = writer.stopped().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
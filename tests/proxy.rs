@@ -0,0 +1,177 @@
+use barrier::proxy::{self, Proxy, ProxyKind};
+use barrier::transport::{self, Transport};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A server echoing whatever it receives.
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+/// A SOCKS5 proxy that only accepts `user` with `pass`.
+async fn socks5_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut client, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 2];
+        client.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0; greeting[1] as usize];
+        client.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&0x02));
+        client.write_all(&[5, 0x02]).await.unwrap();
+
+        let version = client.read_u8().await.unwrap();
+        assert_eq!(version, 1);
+        let username = read_string(&mut client).await;
+        let password = read_string(&mut client).await;
+        if (username.as_str(), password.as_str()) != ("user", "pass") {
+            client.write_all(&[1, 1]).await.unwrap();
+            return;
+        }
+        client.write_all(&[1, 0]).await.unwrap();
+
+        let mut request = [0; 4];
+        client.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..3], &[5, 1, 0]);
+        assert_eq!(request[3], 0x03, "expected a domain name");
+        let host = read_string(&mut client).await;
+        let port = client.read_u16().await.unwrap();
+        let mut server = TcpStream::connect((host.as_str(), port)).await.unwrap();
+        client
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        let _ = copy_bidirectional(&mut client, &mut server).await;
+    });
+    address
+}
+
+async fn read_string(stream: &mut TcpStream) -> String {
+    let length = stream.read_u8().await.unwrap();
+    let mut bytes = vec![0; length as usize];
+    stream.read_exact(&mut bytes).await.unwrap();
+    String::from_utf8(bytes).unwrap()
+}
+
+/// An HTTP proxy that only accepts `user` with `pass`.
+async fn http_proxy() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut client, _) = listener.accept().await.unwrap();
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(client.read_u8().await.unwrap());
+        }
+        let header = String::from_utf8(header).unwrap();
+        if !header.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n") {
+            client
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+            return;
+        }
+        let target = header
+            .strip_prefix("CONNECT ")
+            .and_then(|rest| rest.split(' ').next())
+            .unwrap()
+            .to_string();
+        let mut server = TcpStream::connect(target).await.unwrap();
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = copy_bidirectional(&mut client, &mut server).await;
+    });
+    address
+}
+
+fn transport(address: String, kind: ProxyKind, proxy: SocketAddr, password: &str) -> Transport {
+    Transport::Tcp {
+        address,
+        proxy: Some(Proxy {
+            kind,
+            address: proxy.to_string(),
+            username: Some("user".to_string()),
+            password: Some(password.to_string()),
+        }),
+        connect_timeout: Duration::from_secs(5),
+    }
+}
+
+async fn assert_echo(transport: &Transport) {
+    let mut stream = transport.connect().await.unwrap();
+    stream.write_all(b"\0\0\0\x04CALV").await.unwrap();
+    let mut echo = [0; 8];
+    stream.read_exact(&mut echo).await.unwrap();
+    assert_eq!(&echo, b"\0\0\0\x04CALV");
+}
+
+#[tokio::test]
+async fn socks5_with_password() {
+    let server = echo_server().await;
+    let proxy = socks5_proxy().await;
+    let address = format!("localhost:{}", server.port());
+    assert_echo(&transport(address, ProxyKind::Socks5, proxy, "pass")).await;
+}
+
+#[tokio::test]
+async fn socks5_wrong_password() {
+    let server = echo_server().await;
+    let proxy = socks5_proxy().await;
+    let address = format!("localhost:{}", server.port());
+    match transport(address, ProxyKind::Socks5, proxy, "wrong")
+        .connect()
+        .await
+    {
+        Err(transport::Error::ProxyHandshake {
+            source: proxy::Error::AuthenticationFailed {},
+            ..
+        }) => {}
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("connected with a wrong password"),
+    }
+}
+
+#[tokio::test]
+async fn http_connect() {
+    let server = echo_server().await;
+    let proxy = http_proxy().await;
+    assert_echo(&transport(
+        server.to_string(),
+        ProxyKind::Http,
+        proxy,
+        "pass",
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn http_connect_rejected() {
+    let server = echo_server().await;
+    let proxy = http_proxy().await;
+    match transport(server.to_string(), ProxyKind::Http, proxy, "wrong")
+        .connect()
+        .await
+    {
+        Err(transport::Error::ProxyHandshake {
+            source: proxy::Error::HttpStatus { status },
+            ..
+        }) => assert_eq!(status, "HTTP/1.1 407 Proxy Authentication Required"),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("connected without credentials"),
+    }
+}
@@ -0,0 +1,58 @@
+//! Kept apart from the other tests, as it moves this process's stdout.
+
+use barrier::transport::{self, Transport};
+use hex_literal::hex;
+use nix::unistd::{close, dup, dup2, pipe, read};
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use tokio::io::AsyncWriteExt;
+
+fn read_exact(fd: RawFd, buffer: &mut [u8]) {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = read(fd, &mut buffer[filled..]).unwrap();
+        assert!(read > 0, "stream ended after {:?}", &buffer[..filled]);
+        filled += read;
+    }
+}
+
+fn read_to_end(fd: RawFd) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 256];
+    loop {
+        match read(fd, &mut buffer).unwrap() {
+            0 => return data,
+            read => data.extend_from_slice(&buffer[..read]),
+        }
+    }
+}
+
+#[tokio::test]
+async fn logs_stay_off_the_protocol_stream() {
+    let (stdout, stderr) = (dup(1).unwrap(), dup(2).unwrap());
+    let (protocol_reader, protocol_writer) = pipe().unwrap();
+    let (log_reader, log_writer) = pipe().unwrap();
+    dup2(protocol_writer, 1).unwrap();
+    dup2(log_writer, 2).unwrap();
+    close(protocol_writer).unwrap();
+    close(log_writer).unwrap();
+
+    // What barrierc does at startup with a stdio server configured.
+    transport::take_stdout().unwrap();
+    let mut log = std::io::stdout();
+    log.write_all(b"Config { .. }\n").unwrap();
+    log.flush().unwrap();
+
+    let hello_back = hex!("00 00 00 0f 42 61 72 72 69 65 72 00 01 00 06 00 00 00 01 61");
+    let mut stream = Transport::Stdio.connect().await.unwrap();
+    stream.write_all(&hello_back).await.unwrap();
+    stream.flush().await.unwrap();
+    drop(stream);
+
+    dup2(stdout, 1).unwrap();
+    dup2(stderr, 2).unwrap();
+    let mut received = [0; 20];
+    read_exact(protocol_reader, &mut received);
+    assert_eq!(received, hello_back);
+    assert_eq!(read_to_end(log_reader), b"Config { .. }\n");
+}